@@ -0,0 +1,45 @@
+//! Compares measuring Latin text with a plain font map and with a [`CachedFont`].
+//!
+//! ```text
+//! cargo bench --bench cached_font
+//! ```
+//!
+//! This doesn't use a benchmarking framework, so the times are only rough. Both ways measure
+//! every character on its own, so the only difference is the ASCII table.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use smol_layout::{layout_cached, layout_detailed, monospace_font, CachedFont, LayoutOptions};
+
+const RUNS: usize = 200;
+
+/// Runs `layout` over and over, returning how long each call took.
+fn measure(mut layout: impl FnMut()) -> Duration {
+    layout();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        layout();
+    }
+    start.elapsed() / RUNS as u32
+}
+
+fn main() {
+    let text =
+        "The quick brown fox jumps over the lazy dog, and then it does it again. ".repeat(200);
+    let font = monospace_font(1, text.chars());
+    let options = LayoutOptions::new(60);
+
+    let detailed_time = measure(|| {
+        black_box(layout_detailed(black_box(&text), &options, &font).unwrap());
+    });
+
+    let cached = CachedFont::new(&font);
+    let cached_time = measure(|| {
+        black_box(layout_cached(black_box(&text), &options, &cached).unwrap());
+    });
+
+    println!("{} bytes of Latin text:", text.len());
+    println!("  layout_detailed  {detailed_time:>10.2?}");
+    println!("  layout_cached    {cached_time:>10.2?}");
+}
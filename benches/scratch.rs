@@ -0,0 +1,79 @@
+//! Compares wrapping many short labels with and without a reused [`Scratch`] buffer.
+//!
+//! ```text
+//! cargo bench --bench scratch
+//! ```
+//!
+//! This doesn't use a benchmarking framework, so the times are only rough, but the allocation
+//! counts are exact.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use smol_layout::{apply_newlines, apply_newlines_reusing, monospace_font, Scratch};
+
+/// Counts every allocation, so the two ways can be compared exactly.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const FRAMES: usize = 2000;
+
+/// Runs `frame` over and over, returning how long each call took and how much it allocated.
+fn measure(mut frame: impl FnMut()) -> (Duration, usize) {
+    // Warm up first, so growing the scratch buffer isn't counted
+    frame();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        frame();
+    }
+    let elapsed = start.elapsed() / FRAMES as u32;
+    let allocated = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    (elapsed, allocated / FRAMES)
+}
+
+fn main() {
+    let labels: Vec<String> = (0..40)
+        .map(|idx| format!("Label {idx}: the quick brown fox jumps over the lazy dog"))
+        .collect();
+    let font = monospace_font(1, labels.iter().flat_map(|label| label.chars()));
+
+    let (plain_time, plain_allocs) = measure(|| {
+        for label in &labels {
+            black_box(apply_newlines(black_box(label), 16, &font).unwrap());
+        }
+    });
+
+    let mut scratch = Scratch::new();
+    let (reused_time, reused_allocs) = measure(|| {
+        for label in &labels {
+            black_box(apply_newlines_reusing(black_box(label), 16, &font, &mut scratch).unwrap());
+        }
+    });
+
+    println!("{} labels a frame:", labels.len());
+    println!("  apply_newlines          {plain_time:>10.2?}  {plain_allocs:>4} allocations");
+    println!("  apply_newlines_reusing  {reused_time:>10.2?}  {reused_allocs:>4} allocations");
+}
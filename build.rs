@@ -246,6 +246,11 @@ impl<I: Iterator> IteratorExt for I {}
 fn main() -> Result<(), Box<dyn Error>> {
     println!("cargo:rerun-if-changed=LineBreak.txt");
 
+    // The `no-tables` feature classifies characters without them
+    if env::var_os("CARGO_FEATURE_NO_TABLES").is_some() {
+        return Ok(());
+    }
+
     assert!(NUM_STATES <= 0x3F, "Too many states");
 
     let pair_table = rules2table! {
@@ -334,8 +339,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
 
     // Synthesize all non-"safe" pairs from pair table. There are generally more safe pairs.
-    let unsafe_pairs = (0..NUM_CLASSES).into_iter().flat_map(|j| {
-        (0..NUM_CLASSES).into_iter().filter_map(move |i| {
+    let unsafe_pairs = (0..NUM_CLASSES).flat_map(|j| {
+        (0..NUM_CLASSES).filter_map(move |i| {
             // All states that could have resulted from break class "i"
             let possible_states = pair_table
                 .iter()
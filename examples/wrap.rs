@@ -0,0 +1,138 @@
+//! Wraps a text file to a number of columns, and prints it.
+//!
+//! ```text
+//! cargo run --example wrap -- [--overflow error|break|ellipsis] [--align left|center|right] WIDTH [FILE]
+//! ```
+//!
+//! Every character is one column wide. Without a file, the text is read from standard input.
+
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+use smol_layout::{layout_detailed, monospace_font, LayoutOptions, Overflow};
+
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+struct Args {
+    width: usize,
+    path: Option<String>,
+    overflow: Overflow,
+    align: Align,
+}
+
+const USAGE: &str =
+    "usage: wrap [--overflow error|break|ellipsis] [--align left|center|right] WIDTH [FILE]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut overflow = Overflow::Error;
+    let mut align = Align::Left;
+    let mut positional = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--overflow" => {
+                overflow = match args.next().as_deref() {
+                    Some("error") => Overflow::Error,
+                    Some("break") => Overflow::Break,
+                    Some("ellipsis") => Overflow::Ellipsis("…".to_string()),
+                    _ => return Err("--overflow takes error, break or ellipsis".to_string()),
+                }
+            }
+            "--align" => {
+                align = match args.next().as_deref() {
+                    Some("left") => Align::Left,
+                    Some("center") => Align::Center,
+                    Some("right") => Align::Right,
+                    _ => return Err("--align takes left, center or right".to_string()),
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let width = positional
+        .next()
+        .ok_or("missing WIDTH")?
+        .parse()
+        .map_err(|err| format!("bad WIDTH: {err}"))?;
+    let path = positional.next();
+    if positional.next().is_some() {
+        return Err("too many arguments".to_string());
+    }
+
+    Ok(Args {
+        width,
+        path,
+        overflow,
+        align,
+    })
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let mut text = String::new();
+    match &args.path {
+        Some(path) => {
+            text = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        }
+        None => {
+            std::io::stdin()
+                .read_to_string(&mut text)
+                .map_err(|err| format!("stdin: {err}"))?;
+        }
+    }
+
+    // The ellipsis isn't in the text, but it's drawn, so it needs a width
+    let font = monospace_font(1, text.chars().chain(['…']));
+    let options = LayoutOptions {
+        overflow: args.overflow,
+        ..LayoutOptions::new(args.width)
+    };
+    let result = layout_detailed(&text, &options, &font).map_err(|err| err.to_string())?;
+
+    // The empty line after a trailing line break is already ended by the one before it
+    let mut lines: Vec<_> = result.lines().zip(result.trimmed_line_widths()).collect();
+    if lines.len() > 1 && lines.last().is_some_and(|(line, _)| line.is_empty()) {
+        lines.pop();
+    }
+
+    let mut out = std::io::stdout().lock();
+    for (line, width) in lines {
+        let line = line.trim_end();
+        let room = args.width.saturating_sub(width);
+        let pad = match args.align {
+            _ if line.is_empty() => 0,
+            Align::Left => 0,
+            Align::Center => room / 2,
+            Align::Right => room,
+        };
+        writeln!(out, "{:pad$}{line}", "").map_err(|err| format!("stdout: {err}"))?;
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("wrap: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
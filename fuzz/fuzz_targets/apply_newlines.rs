@@ -0,0 +1,62 @@
+//! Throws arbitrary text and fonts at the layout functions.
+//!
+//! The input is a small header followed by the text:
+//! - byte 0: the max width
+//! - byte 1: flags; bit 0 selects `Overflow::Break`, bit 1 leaves some characters out of the font
+//! - byte 2: a seed used to pick each character's width
+//! - the rest: the text, which must be valid UTF-8
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use smol_layout::{
+    apply_newlines, hard_lines, layout_detailed, offset_at, width_prefix_sums, LayoutOptions,
+    Overflow,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let [max_width, flags, seed, text @ ..] = data else {
+        return;
+    };
+    let Ok(text) = std::str::from_utf8(text) else {
+        return;
+    };
+
+    let font: HashMap<char, usize> = text
+        .chars()
+        .map(|c| (c, (c as usize ^ *seed as usize) % 5))
+        .filter(|&(c, width)| flags & 2 == 0 || (c as usize + width) % 7 != 0)
+        .collect();
+    let max_width = *max_width as usize;
+    let without_newlines = |s: &str| s.replace('\n', "");
+
+    if let Ok(output) = apply_newlines(text, max_width, &font) {
+        assert_eq!(without_newlines(&output), without_newlines(text));
+    }
+
+    let options = LayoutOptions {
+        overflow: if flags & 1 == 0 {
+            Overflow::Error
+        } else {
+            Overflow::Break
+        },
+        ..LayoutOptions::new(max_width)
+    };
+    if let Ok(result) = layout_detailed(text, &options, &font) {
+        assert_eq!(without_newlines(&result.text), without_newlines(text));
+        assert!(result
+            .emergency_breaks
+            .iter()
+            .all(|&i| text.is_char_boundary(i)));
+    }
+
+    for line in hard_lines(text) {
+        if let Ok(sums) = width_prefix_sums(line, &font) {
+            assert_eq!(sums.len(), line.chars().count() + 1);
+        }
+        if let Ok(offset) = offset_at(line, max_width, &font) {
+            assert!(line.is_char_boundary(offset));
+        }
+    }
+});
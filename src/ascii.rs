@@ -0,0 +1,79 @@
+//! A minimal break classifier for the `no-tables` feature, used instead of the generated Unicode
+//! tables.
+//!
+//! Only the classes that matter for plain ASCII text are recognized: spaces and tabs allow a
+//! break after them, as does a hyphen, and the hard line break characters force one. Every other
+//! character is treated as [`BreakClass::Alphabetic`], so there are no break opportunities
+//! between CJK ideographs, and scripts that need more than spaces to break aren't supported.
+
+use crate::{BreakClass, BreakOpportunity};
+
+pub(crate) fn break_property(codepoint: u32) -> BreakClass {
+    match char::from_u32(codepoint) {
+        Some(' ') => BreakClass::Space,
+        Some('\t') => BreakClass::After,
+        Some('-') => BreakClass::Hyphen,
+        Some('\n') => BreakClass::LineFeed,
+        Some('\r') => BreakClass::CarriageReturn,
+        Some('\u{85}') => BreakClass::NextLine,
+        Some('\u{b}' | '\u{c}' | '\u{2028}' | '\u{2029}') => BreakClass::Mandatory,
+        _ => BreakClass::Alphabetic,
+    }
+}
+
+/// The state of the scan: the class of the last character, or `None` at the start of the text.
+pub(crate) type ScanState = Option<BreakClass>;
+
+pub(crate) const SCAN_START: ScanState = None;
+
+/// Moves the scan on to a character of break class `class`, or the end of the text if it's
+/// `None`, and returns the opportunity before it.
+pub(crate) fn scan_step(
+    prev: &mut ScanState,
+    class: Option<BreakClass>,
+) -> Option<BreakOpportunity> {
+    use BreakClass::*;
+    use BreakOpportunity::{Allowed, Mandatory as Forced};
+
+    let break_op = match (*prev, class) {
+        // Never break at the start of text
+        (None, _) => None,
+        (Some(CarriageReturn), Some(LineFeed)) => None,
+        (Some(Mandatory | CarriageReturn | LineFeed | NextLine), _) => Some(Forced),
+        // Always break at the end of text
+        (_, None) => Some(Forced),
+        (_, Some(Mandatory | CarriageReturn | LineFeed | NextLine)) => None,
+        (_, Some(Space | After | Hyphen)) => None,
+        (Some(Space | After | Hyphen), _) => Some(Allowed),
+        _ => None,
+    };
+    *prev = class;
+    break_op
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{line_break_opportunities, linebreaks};
+    use BreakOpportunity::{Allowed, Mandatory};
+
+    #[test]
+    fn ascii_opportunities() {
+        assert_eq!(line_break_opportunities("").count(), 0);
+        assert_eq!(
+            line_break_opportunities("a b  c-d\t\te").collect::<Vec<_>>(),
+            [(2, Allowed), (5, Allowed), (7, Allowed), (10, Allowed)]
+        );
+        assert_eq!(
+            line_break_opportunities("a\r\nb\rc\u{2028}d").collect::<Vec<_>>(),
+            [(3, Mandatory), (5, Mandatory), (9, Mandatory)]
+        );
+
+        // No breaks between ideographs
+        assert_eq!(line_break_opportunities("中中中").count(), 0);
+        assert_eq!(
+            linebreaks("a\n").collect::<Vec<_>>(),
+            [(0, None), (1, None), (2, Some(Mandatory))]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! A reusable layout setup, for laying out many texts the same way.
+
+use std::collections::HashMap;
+
+use crate::layout::layout_validated;
+use crate::{validate, LayoutOptions, LayoutResult, LineBreakErr, OptionError};
+
+/// Options and a font that have been checked once, to lay out any number of texts with.
+///
+/// This lays out text the same way as calling [`layout_detailed`](crate::layout_detailed) with
+/// the options and font each time, but the options are checked with [`validate`] only once, when
+/// the engine is made. So a mistake in them is found at startup instead of on the first request,
+/// and [`LayoutEngine::wrap`] doesn't check them again, and only fails because of the text.
+///
+/// The engine is `Send` and `Sync`, and [`LayoutEngine::wrap`] only takes `&self`, so one engine
+/// can be shared between threads in an [`Arc`](std::sync::Arc) and used from all of them at once
+/// without locking. Nothing is cached or changed between calls: each one gets its own working
+/// memory, and the result only depends on the text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutEngine {
+    options: LayoutOptions,
+    font: HashMap<char, usize>,
+}
+
+impl LayoutEngine {
+    /// Makes an engine that lays out text with `options` and `font`, or returns why the options
+    /// can't be used.
+    pub fn new(options: LayoutOptions, font: HashMap<char, usize>) -> Result<Self, OptionError> {
+        validate(&options)?;
+        Ok(LayoutEngine { options, font })
+    }
+
+    /// Lays out `text`, as [`layout_detailed`](crate::layout_detailed) would with the engine's
+    /// options and font.
+    pub fn wrap(&self, text: &str) -> Result<LayoutResult, LineBreakErr> {
+        layout_validated(text, &self.options, &self.font)
+    }
+
+    /// Returns the options text is laid out with.
+    pub fn options(&self) -> &LayoutOptions {
+        &self.options
+    }
+
+    /// Returns the font text is measured with.
+    pub fn font(&self) -> &HashMap<char, usize> {
+        &self.font
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout_detailed;
+    use crate::tests::make_font;
+    use std::sync::Arc;
+
+    #[test]
+    fn shared_engine() {
+        let options = LayoutOptions::new(5);
+        let engine = Arc::new(LayoutEngine::new(options.clone(), make_font()).unwrap());
+
+        let threads: Vec<_> = (0..4)
+            .map(|idx| {
+                let engine = Arc::clone(&engine);
+                std::thread::spawn(move || engine.wrap(&"ab cd ".repeat(idx + 1)))
+            })
+            .collect();
+        for (idx, thread) in threads.into_iter().enumerate() {
+            let text = "ab cd ".repeat(idx + 1);
+            assert_eq!(
+                thread.join().unwrap(),
+                layout_detailed(&text, &options, &make_font())
+            );
+        }
+
+        assert_eq!(
+            LayoutEngine::new(
+                LayoutOptions {
+                    max_lines: Some(0),
+                    ..options
+                },
+                make_font()
+            ),
+            Err(OptionError::NoLines)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Helpers for building and checking font maps.
+
+use std::collections::{HashMap, HashSet};
+
+/// A source of character widths, such as a text shaper, that can take context into account.
+///
+/// This is the general form of the `HashMap<char, usize>` fonts the rest of the crate takes, for
+/// sources like `swash` or `cosmic-text` that adjust advances for kerning. The layout functions
+/// pass the previous character on the same line, or `None` at the start of a line, so a pair is
+/// never kerned across a line break. Characters may be measured more than once, and not
+/// strictly in order, when the layout backtracks to an earlier break.
+pub trait Advance {
+    /// Returns the advance width of `ch` when it follows `prev`, or `None` if it can't be drawn.
+    fn advance(&mut self, ch: char, prev: Option<char>) -> Option<usize>;
+
+    /// Returns the advance width of a whole cluster of characters, such as a base character and
+    /// the combining marks on it, when it follows `prev`, or the character that can't be drawn.
+    ///
+    /// Override this when marks change the advance of their base, or have advances of their own
+    /// that should be combined with it. By default, this adds up the advances of the characters.
+    fn advance_cluster(&mut self, cluster: &str, mut prev: Option<char>) -> Result<usize, char> {
+        let mut width = 0;
+        for c in cluster.chars() {
+            width += self.advance(c, prev).ok_or(c)?;
+            prev = Some(c);
+        }
+        Ok(width)
+    }
+}
+
+impl Advance for HashMap<char, usize> {
+    fn advance(&mut self, ch: char, _prev: Option<char>) -> Option<usize> {
+        self.get(&ch).copied()
+    }
+}
+
+/// A font map with the widths of ASCII characters copied into a table, so the common case of
+/// Latin text doesn't hash every character it measures.
+///
+/// Build one once for a font that is used for many layouts, and lay out with it using
+/// [`layout_cached`](crate::layout_cached), which gives the same result as
+/// [`layout_detailed`](crate::layout_detailed) with the map. Other characters are looked up in
+/// the map. It can also be used as an [`Advance`] source, but
+/// [`layout_advances`](crate::layout_advances) clusters combining sequences, which costs more
+/// than the table saves.
+#[derive(Debug, Clone)]
+pub struct CachedFont<'a> {
+    ascii: [Option<usize>; 128],
+    font: &'a HashMap<char, usize>,
+}
+
+impl<'a> CachedFont<'a> {
+    /// Copies the widths of the ASCII characters out of `font`.
+    pub fn new(font: &'a HashMap<char, usize>) -> Self {
+        let mut ascii = [None; 128];
+        for (c, width) in ascii.iter_mut().enumerate() {
+            *width = font.get(&(c as u8 as char)).copied();
+        }
+        CachedFont { ascii, font }
+    }
+
+    /// Returns the width of `c`, like [`HashMap::get`] on the font.
+    pub fn get(&self, c: char) -> Option<usize> {
+        match self.ascii.get(c as usize) {
+            Some(&width) => width,
+            None => self.font.get(&c).copied(),
+        }
+    }
+}
+
+impl Advance for CachedFont<'_> {
+    fn advance(&mut self, ch: char, _prev: Option<char>) -> Option<usize> {
+        self.get(ch)
+    }
+}
+
+/// Checks that `font` has a width for every character in `required`.
+///
+/// On failure, returns each missing character once, in the order they were first seen.
+pub fn validate_coverage(
+    font: &HashMap<char, usize>,
+    required: impl IntoIterator<Item = char>,
+) -> Result<(), Vec<char>> {
+    let mut seen = HashSet::new();
+    let missing: Vec<char> = required
+        .into_iter()
+        .filter(|c| !font.contains_key(c) && seen.insert(*c))
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(missing)
+    }
+}
+
+/// Returns a font map where every character in `chars` has the same width.
+pub fn monospace_font(width: usize, chars: impl IntoIterator<Item = char>) -> HashMap<char, usize> {
+    chars.into_iter().map(|c| (c, width)).collect()
+}
+
+/// Returns a font map where every printable ASCII character (`' '..='~'`) has the same width.
+pub fn ascii_monospace(width: usize) -> HashMap<char, usize> {
+    monospace_font(width, ' '..='~')
+}
+
+/// Returns a font map for a terminal, measured in cells.
+///
+/// Printable ASCII and Latin-1 characters are one cell wide, and the common CJK ideographs, kana
+/// and fullwidth forms are two. This is a rough stand-in for `wcwidth`, good enough for trying
+/// the crate out, but it doesn't cover combining marks or emoji.
+#[cfg(feature = "sample-fonts")]
+pub fn terminal_font() -> HashMap<char, usize> {
+    let narrow = (' '..='~').chain('\u{A0}'..='\u{FF}').map(|c| (c, 1));
+    let wide = ('\u{3040}'..='\u{30FF}')
+        .chain('\u{4E00}'..='\u{9FFF}')
+        .chain('\u{FF01}'..='\u{FF60}')
+        .map(|c| (c, 2));
+    narrow.chain(wide).collect()
+}
+
+/// Returns a font map for printable ASCII in a small proportional pixel font, in pixels.
+///
+/// Most glyphs are 4 pixels wide plus 1 pixel of spacing, with narrow glyphs like `i` and `.`
+/// and wide ones like `m` and `W`, so it is useful for testing wrapping with uneven widths.
+#[cfg(feature = "sample-fonts")]
+pub fn sample_pixel_font() -> HashMap<char, usize> {
+    (' '..='~')
+        .map(|c| {
+            let width = match c {
+                '!' | '\'' | ',' | '.' | ':' | ';' | '|' | 'i' | 'l' => 2,
+                '(' | ')' | '[' | ']' | '`' | 'I' | 'j' | 't' => 3,
+                ' ' | '"' | '1' | 'f' | 'k' | 'r' | '{' | '}' => 4,
+                '#' | '%' | '&' | '@' | 'M' | 'W' | 'm' | 'w' => 6,
+                _ => 5,
+            };
+            (c, width)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::make_font;
+    use crate::{layout_advances, layout_cached, layout_detailed, LayoutOptions, Overflow};
+
+    #[test]
+    fn cached_font() {
+        let mut font = make_font();
+        font.insert('W', 3);
+        font.insert('中', 2);
+        font.remove(&'~');
+        let cached = CachedFont::new(&font);
+
+        for c in ['a', 'W', '\0', '\u{7F}', 'é', '中', '~', '≤'] {
+            assert_eq!(cached.get(c), font.get(&c).copied(), "{:?}", c);
+        }
+
+        let opts = LayoutOptions {
+            overflow: Overflow::Break,
+            ..LayoutOptions::new(6)
+        };
+        let text = "aW 中中 WWW aaaa";
+        assert_eq!(
+            layout_cached(text, &opts, &cached),
+            layout_detailed(text, &opts, &font)
+        );
+        assert_eq!(
+            layout_advances(text, &opts, &mut CachedFont::new(&font)),
+            layout_detailed(text, &opts, &font)
+        );
+    }
+
+    #[test]
+    fn coverage() {
+        let font = make_font();
+
+        assert_eq!(validate_coverage(&font, ' '..='~'), Ok(()));
+        assert_eq!(validate_coverage(&font, []), Ok(()));
+        assert_eq!(
+            validate_coverage(&font, "a≤b≥≤".chars()),
+            Err(vec!['≤', '≥'])
+        );
+    }
+
+    #[test]
+    fn monospace() {
+        let font = monospace_font(3, "abc".chars());
+        assert_eq!(font.len(), 3);
+        assert_eq!(font[&'b'], 3);
+
+        let font = ascii_monospace(2);
+        assert_eq!(font.len(), 95);
+        assert!(font.values().all(|&width| width == 2));
+        assert_eq!(validate_coverage(&font, "Hello, world!".chars()), Ok(()));
+        assert_eq!(validate_coverage(&font, "\t".chars()), Err(vec!['\t']));
+    }
+
+    #[test]
+    #[cfg(feature = "sample-fonts")]
+    fn sample_fonts() {
+        let font = terminal_font();
+        assert_eq!(
+            validate_coverage(&font, "Hello, wörld! 中文 カナ".chars()),
+            Ok(())
+        );
+        assert_eq!(font[&'a'], 1);
+        assert_eq!(font[&'中'], 2);
+
+        let font = sample_pixel_font();
+        assert_eq!(validate_coverage(&font, ' '..='~'), Ok(()));
+        assert_eq!(font.len(), 95);
+        assert!(font[&'i'] < font[&'a'] && font[&'a'] < font[&'m']);
+    }
+}
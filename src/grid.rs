@@ -0,0 +1,168 @@
+//! Wrapping into a grid of terminal cells.
+
+use crate::{layout_advances, Advance, LayoutOptions, LineBreakErr};
+
+/// One cell of a grid returned by [`to_grid`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Cell {
+    /// Nothing is drawn in the cell, after the end of a line.
+    #[default]
+    Empty,
+    /// The characters drawn in the cell: one that is one or two cells wide, followed by any
+    /// zero-width characters that go with it.
+    Text(String),
+    /// The cell is covered by the wide character in the cell before it.
+    Continuation,
+}
+
+/// Wraps `string` to `max_cols` terminal cells, and returns each line as a row of cells.
+///
+/// Every row is exactly `max_cols` cells, padded with [`Cell::Empty`], and there is one row per
+/// line, as [`apply_newlines`](crate::apply_newlines) would break it. CJK ideographs, kana,
+/// Hangul, fullwidth forms and emoji are two cells wide, taking a [`Cell::Text`] followed by a
+/// [`Cell::Continuation`], and most other characters are one.
+///
+/// Zero-width characters, like combining marks, joiners and variation selectors, are drawn in the
+/// same cell as the character before them, and are never wrapped apart from it. Any at the start
+/// of a line go in its first cell, before the character drawn there. Hard line breaks take no
+/// cells, and other control characters, including tabs, have no width, so they are reported as
+/// [`LineBreakErr::MissingCharacterWidth`].
+pub fn to_grid(string: &str, max_cols: usize) -> Result<Vec<Vec<Cell>>, LineBreakErr> {
+    let result = layout_advances(string, &LayoutOptions::new(max_cols), &mut TerminalCells)?;
+
+    let rows = result
+        .lines()
+        .map(|line| {
+            let mut row = Vec::with_capacity(max_cols);
+            let mut leading = String::new();
+            for c in line.chars() {
+                match cell_width(c) {
+                    Some(0) => match row.iter_mut().rev().find_map(|cell| match cell {
+                        Cell::Text(text) => Some(text),
+                        _ => None,
+                    }) {
+                        Some(text) => text.push(c),
+                        None => leading.push(c),
+                    },
+                    width => {
+                        let mut text = std::mem::take(&mut leading);
+                        text.push(c);
+                        row.push(Cell::Text(text));
+                        if width == Some(2) {
+                            row.push(Cell::Continuation);
+                        }
+                    }
+                }
+            }
+            if !leading.is_empty() && max_cols > 0 {
+                row.push(Cell::Text(leading));
+            }
+            row.resize(max_cols, Cell::Empty);
+            row
+        })
+        .collect();
+    Ok(rows)
+}
+
+/// Measures characters in terminal cells, for [`to_grid`].
+struct TerminalCells;
+
+impl Advance for TerminalCells {
+    fn advance(&mut self, ch: char, _prev: Option<char>) -> Option<usize> {
+        cell_width(ch)
+    }
+}
+
+/// Returns how many terminal cells `c` takes up, or `None` for control characters.
+fn cell_width(c: char) -> Option<usize> {
+    match c {
+        _ if c.is_control() => None,
+        '\u{300}'..='\u{36F}'
+        | '\u{483}'..='\u{489}'
+        | '\u{591}'..='\u{5BD}'
+        | '\u{610}'..='\u{61A}'
+        | '\u{64B}'..='\u{65F}'
+        | '\u{1160}'..='\u{11FF}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{200B}'..='\u{200F}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{FE20}'..='\u{FE2F}'
+        | '\u{FEFF}'
+        | '\u{E0100}'..='\u{E01EF}' => Some(0),
+        '\u{1100}'..='\u{115F}'
+        | '\u{2E80}'..='\u{303E}'
+        | '\u{3041}'..='\u{33FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{A000}'..='\u{A4CF}'
+        | '\u{AC00}'..='\u{D7A3}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FE30}'..='\u{FE4F}'
+        | '\u{FF00}'..='\u{FF60}'
+        | '\u{FFE0}'..='\u{FFE6}'
+        | '\u{1F300}'..='\u{1F64F}'
+        | '\u{1F900}'..='\u{1F9FF}'
+        | '\u{20000}'..='\u{3FFFD}' => Some(2),
+        _ => Some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Cell {
+        Cell::Text(s.to_string())
+    }
+
+    #[test]
+    fn grid_cells() {
+        use Cell::{Continuation, Empty};
+
+        assert_eq!(
+            to_grid("ab cd\ne", 3).unwrap(),
+            [
+                vec![text("a"), text("b"), text(" ")],
+                vec![text("c"), text("d"), Empty],
+                vec![text("e"), Empty, Empty],
+            ]
+        );
+        assert_eq!(to_grid("", 2).unwrap(), [vec![Empty, Empty]]);
+
+        // Wide characters take two cells, and combining marks share a cell with their base
+        assert_eq!(
+            to_grid("中e\u{301}", 3).unwrap(),
+            [vec![text("中"), Continuation, text("e\u{301}")]]
+        );
+        assert_eq!(
+            to_grid("\u{301}a\n\u{200B}", 2).unwrap(),
+            [vec![text("\u{301}a"), Empty], vec![text("\u{200B}"), Empty]]
+        );
+
+        assert_eq!(
+            to_grid("a\tb", 4).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('\t')
+        );
+    }
+
+    #[cfg(not(feature = "no-tables"))]
+    #[test]
+    fn grid_wraps_wide_characters() {
+        use Cell::Continuation;
+
+        assert_eq!(
+            to_grid("中文字", 4).unwrap(),
+            [
+                vec![text("中"), Continuation, text("文"), Continuation],
+                vec![text("字"), Continuation, Cell::Empty, Cell::Empty],
+            ]
+        );
+        assert!(matches!(
+            to_grid("中", 1).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity { offset: 0, .. }
+        ));
+    }
+}
@@ -0,0 +1,407 @@
+//! Incremental re-layout for editors.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::layout::{enabled_escapes, glyphs, sum_chars, LineEnd, Lines};
+use crate::{is_hard_break, Fit, LayoutOptions, LeadingWhitespace, LineBreakErr};
+
+/// The result of [`rewrap_range`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Rewrap {
+    /// The byte offset of the start of each line of the edited text, the same as
+    /// [`LayoutResult::line_starts`](crate::LayoutResult::line_starts) for a full layout.
+    pub line_starts: Vec<usize>,
+    /// The lines that were wrapped again, as indices into `line_starts`. Every other line is the
+    /// same as in the old layout, apart from being shifted by the edit.
+    pub changed: Range<usize>,
+}
+
+/// Updates a layout after an edit, without wrapping the whole text again.
+///
+/// `text` is the text after the edit, and `line_starts` is the layout of the text before it, as
+/// returned in [`LayoutResult::line_starts`](crate::LayoutResult::line_starts). `edit` is the byte
+/// range of the old text that was replaced, and `inserted` is the length of its replacement.
+/// The options and font must be the same ones used for the old layout, except that
+/// [`LayoutOptions::no_break_spans`] are offsets into the edited text, so they move with it.
+///
+/// Wrapping starts again from the line containing the edit, or an earlier one if the edit could
+/// change where it broke (for example, shortening the first word of a line can pull it up onto
+/// the line before). It stops as soon as a line after the edit starts at the same place it did
+/// in the old layout, or at the latest, at the next hard line break after the edit.
+pub fn rewrap_range(
+    text: &str,
+    line_starts: &[usize],
+    edit: Range<usize>,
+    inserted: usize,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<Rewrap, LineBreakErr> {
+    let edit_end = edit.start + inserted;
+    let to_old = |offset: usize| offset - inserted + edit.len();
+    let to_new = |offset: usize| offset + inserted - edit.len();
+
+    let char_end = |idx: usize| idx + text[idx..].chars().next().map_or(0, char::len_utf8);
+    let paragraph_start = |offset: usize| text[..offset].rfind(is_hard_break).map_or(0, char_end);
+
+    // Escapes right before the edit may have been cut short by it, and take their break
+    // opportunity from the character after them, so they count as part of it
+    let mut edit_start = edit.start;
+    while let Some(esc) = text[..edit_start]
+        .rfind('\x1b')
+        .filter(|_| options.ansi_escapes)
+    {
+        let escape_prefix = match text.as_bytes()[esc + 1..edit_start].split_first() {
+            None => true,
+            Some((b'[', rest)) => {
+                let params = match rest.split_last() {
+                    Some((last, params)) if (0x40..=0x7E).contains(last) => params,
+                    _ => rest,
+                };
+                params.iter().all(|b| (0x20..=0x3F).contains(b))
+            }
+            Some(_) => false,
+        };
+        if !escape_prefix {
+            break;
+        }
+        edit_start = esc;
+    }
+
+    // Break opportunities before the edit are the same as they were
+    let edit_paragraph = paragraph_start(edit_start);
+    let edit_options = spans_from(options, edit_paragraph);
+    let paragraph_escapes = enabled_escapes(&text[edit_paragraph..], &edit_options);
+    let opportunities: Vec<usize> =
+        glyphs(&text[edit_paragraph..], &edit_options, &paragraph_escapes)
+            .take_while(|&(idx, _, _)| edit_paragraph + idx < edit_start)
+            .filter(|&(_, _, break_op)| break_op.is_some())
+            .map(|(idx, _, _)| edit_paragraph + idx)
+            .collect();
+
+    // Start from the line containing the edit, or earlier if the edit could have changed where an
+    // earlier line broke. When deciding that, a soft-wrapped line looked no further than the
+    // first break opportunity after the start of the next line, unless it could pass over
+    // opportunities that fit, in which case the whole paragraph is wrapped again.
+    let looks_ahead = options.soft_hyphen.is_some()
+        || options.continuation_marker.is_some()
+        || options.fit != Fit::Last
+        || options.prefer_spaces;
+    let mut first = line_starts
+        .partition_point(|&start| start <= edit_start)
+        .saturating_sub(1);
+    while first > 0 {
+        let start = line_starts[first];
+        let next_opportunity = opportunities[opportunities.partition_point(|&idx| idx <= start)..]
+            .first()
+            .copied();
+        if start <= edit_paragraph || (next_opportunity.is_some() && !looks_ahead) {
+            break;
+        }
+        first -= 1;
+    }
+    let restart = line_starts.get(first).copied().unwrap_or(0);
+
+    // Only the hard lines from the restart to the end of the edit need to be wrapped
+    let paragraph_start = paragraph_start(restart);
+    let paragraph_end = text[edit_end..]
+        .find(is_hard_break)
+        .map_or(text.len(), |idx| {
+            let idx = edit_end + idx;
+            if text[idx..].starts_with("\r\n") {
+                idx + "\r\n".len()
+            } else {
+                char_end(idx)
+            }
+        });
+
+    let paragraph = &text[paragraph_start..paragraph_end];
+    let paragraph_options = spans_from(options, paragraph_start);
+    let width_of = sum_chars(|_, c| font.get(&c).copied());
+    let mut lines = Lines::new(paragraph, &paragraph_options, width_of);
+    // Lines after a hard break only start after whitespace if it was trimmed off them, which never
+    // happens to the first line of the text
+    let starts_paragraph = restart == paragraph_start
+        || (options.leading_whitespace == LeadingWhitespace::TrimAll
+            && paragraph_start > 0
+            && text[paragraph_start..restart]
+                .chars()
+                .all(char::is_whitespace));
+    let prev_end = if restart == 0 {
+        None
+    } else if starts_paragraph {
+        Some(LineEnd::Hard)
+    } else {
+        Some(LineEnd::Soft)
+    };
+    lines.seek(restart - paragraph_start, first, prev_end);
+
+    let mut result = Rewrap {
+        line_starts: line_starts[..first].to_vec(),
+        changed: first..first,
+    };
+    // Where to pick the old layout back up, if anything follows the wrapped lines
+    let mut resume = (paragraph_end < text.len()).then_some(paragraph_end);
+    // Whether a line is laid out differently at the start of a paragraph
+    let starts_differ = options.leading_whitespace == LeadingWhitespace::TrimContinuation
+        || options.first_line_indent.is_some();
+
+    // Forced breaks are counted from the opportunity before them, which the edit can move, so
+    // they can change anywhere up to the next hard break
+    let forced_breaks = options.force_break_every.is_some();
+
+    for line in lines {
+        let start = paragraph_start
+            + line
+                .map_err(|err| match err {
+                    LineBreakErr::NoLegalLinebreakOpportunity { offset, run } => {
+                        LineBreakErr::NoLegalLinebreakOpportunity {
+                            offset: paragraph_start + offset,
+                            run,
+                        }
+                    }
+                    LineBreakErr::UnexpectedControl { ch, offset } => {
+                        LineBreakErr::UnexpectedControl {
+                            ch,
+                            offset: paragraph_start + offset,
+                        }
+                    }
+                    LineBreakErr::Stalled { offset } => LineBreakErr::Stalled {
+                        offset: paragraph_start + offset,
+                    },
+                    err => err,
+                })?
+                .start;
+
+        // The next hard line is left to the old layout, which starts it the same way
+        if start == paragraph_end && paragraph_end < text.len() {
+            break;
+        }
+
+        // Once a line after the edit starts where an old one did, the rest of the layout is
+        // the same as before, as long as it's still known to start a paragraph or not, is
+        // still the first line of the text or not, and doesn't start partway through an edited
+        // word that it could break at a soft hyphen in
+        let before =
+            text[..start].trim_end_matches(|c: char| c.is_whitespace() && !is_hard_break(c));
+        let same_kind = !forced_breaks
+            && (!starts_differ || before.len() > edit_end)
+            && (options.first_line_used_width == 0
+                || (start == 0) == (start + edit.len() == inserted))
+            && (options.hyphen_min_left == 0
+                || text[..start].rfind(char::is_whitespace).map_or(0, char_end) > edit_end);
+        if start >= edit_end && same_kind && line_starts.binary_search(&to_old(start)).is_ok() {
+            resume = Some(start);
+            break;
+        }
+        result.line_starts.push(start);
+    }
+    result.changed.end = result.line_starts.len();
+
+    if let Some(resume) = resume {
+        let old_resume =
+            line_starts.partition_point(|&start| start < edit.end || to_new(start) < resume);
+        result
+            .line_starts
+            .extend(line_starts[old_resume..].iter().map(|&start| to_new(start)));
+    }
+
+    // The lines picked back up from the old layout weren't counted against the limit
+    if let Some(limit) = options
+        .max_lines
+        .filter(|&limit| result.line_starts.len() > limit)
+    {
+        return Err(LineBreakErr::TooManyLines { limit });
+    }
+
+    Ok(result)
+}
+
+/// Returns `options` with [`LayoutOptions::no_break_spans`] moved to be offsets from `start`, for
+/// wrapping only the text after it.
+fn spans_from(options: &LayoutOptions, start: usize) -> Cow<'_, LayoutOptions> {
+    if start == 0 || options.no_break_spans.is_empty() {
+        return Cow::Borrowed(options);
+    }
+    Cow::Owned(LayoutOptions {
+        no_break_spans: options
+            .no_break_spans
+            .iter()
+            .map(|span| span.start.saturating_sub(start)..span.end.saturating_sub(start))
+            .collect(),
+        ..options.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{make_font, Rng};
+    use crate::{count_lines, layout_detailed, Overflow};
+
+    fn options(max_width: usize) -> LayoutOptions {
+        LayoutOptions {
+            overflow: Overflow::Break,
+            ..LayoutOptions::new(max_width)
+        }
+    }
+
+    /// Applies an edit and checks that re-wrapping matches wrapping from scratch, including any
+    /// error. The options are for the old text, and any spans in them move with the edit.
+    fn check_edit(
+        old: &str,
+        edit: Range<usize>,
+        replacement: &str,
+        options: &LayoutOptions,
+        font: &HashMap<char, usize>,
+    ) -> Result<Rewrap, LineBreakErr> {
+        let new = format!("{}{}{}", &old[..edit.start], replacement, &old[edit.end..]);
+        let moved = |offset: usize| {
+            if offset <= edit.start {
+                offset
+            } else {
+                offset.max(edit.end) - edit.len() + replacement.len()
+            }
+        };
+        let new_options = LayoutOptions {
+            no_break_spans: options
+                .no_break_spans
+                .iter()
+                .map(|span| moved(span.start)..moved(span.end))
+                .collect(),
+            ..options.clone()
+        };
+
+        let old_lines = layout_detailed(old, options, font).unwrap().line_starts;
+        let expected = layout_detailed(&new, &new_options, font).map(|result| result.line_starts);
+
+        let rewrap = rewrap_range(
+            &new,
+            &old_lines,
+            edit.clone(),
+            replacement.len(),
+            &new_options,
+            font,
+        );
+        assert_eq!(
+            rewrap.as_ref().map(|rewrap| &rewrap.line_starts),
+            expected.as_ref(),
+            "{:?} -> {:?}",
+            old,
+            new
+        );
+        rewrap
+    }
+
+    #[test]
+    fn rewrap_stops_when_converged() {
+        let text = "aaa bbb ccc ddd eee fff ggg hhh iii jjj";
+        let opts = options(8);
+
+        // "bbb " -> "b " pulls nothing up yet, and the layout converges on the next line
+        let rewrap = check_edit(text, 4..8, "b ", &opts, &make_font()).unwrap();
+        assert_eq!(rewrap.line_starts, [0, 6, 14, 22, 30]);
+        assert_eq!(rewrap.changed, 0..1);
+
+        // Or a longer word ripples forward through every later line
+        let rewrap = check_edit(
+            "aa bb cc dd ee ff gg hh",
+            0..2,
+            "aaa",
+            &options(6),
+            &make_font(),
+        )
+        .unwrap();
+        assert_eq!(rewrap.line_starts, [0, 4, 10, 16, 22]);
+        assert_eq!(rewrap.changed, 0..5);
+
+        // Edits never ripple past a hard line break
+        let text = "aaa bbb ccc\nddd eee fff";
+        let rewrap = check_edit(text, 4..7, "bbbbbb", &opts, &make_font()).unwrap();
+        assert_eq!(rewrap.line_starts, [0, 4, 11, 15, 23]);
+        assert_eq!(rewrap.changed, 0..2);
+    }
+
+    #[test]
+    fn rewrap_matches_full_layout() {
+        for seed in 0..1000 {
+            let mut rng = Rng::new(seed);
+            let old = rng.text();
+            let mut font = rng.font();
+            let mut opts = options(1 + rng.below(20));
+            opts.leading_whitespace = [
+                LeadingWhitespace::Keep,
+                LeadingWhitespace::TrimContinuation,
+                LeadingWhitespace::TrimAll,
+            ][rng.below(3)];
+            if rng.below(2) == 0 {
+                opts.break_after = vec!['-', ','];
+            }
+            if rng.below(2) == 0 {
+                opts.no_break_before = vec![',', '.'];
+            }
+            if rng.below(4) == 0 {
+                opts.no_break_after = vec!['-', 'é'];
+            }
+            if font
+                .get(&' ')
+                .is_some_and(|&space| 2 * space < opts.max_width)
+                && rng.below(2) == 0
+            {
+                opts.first_line_indent = Some("  ".to_string());
+            }
+            if rng.below(2) == 0 {
+                opts.soft_hyphen = Some("-".to_string());
+                opts.fit = [Fit::Last, Fit::Closest][rng.below(2)];
+                opts.hyphen_min_left = rng.below(3);
+                opts.hyphen_min_right = rng.below(3);
+            }
+            opts.prefer_spaces = rng.below(2) == 0;
+            opts.width_inclusive = rng.below(4) != 0;
+            opts.collapse_blank_lines = rng.below(4) == 0;
+            if rng.below(4) == 0 {
+                opts.force_break_every = Some(1 + rng.below(8));
+            }
+            if rng.below(4) == 0 {
+                opts.zero_width = vec!['x', 'é'];
+            }
+            if font.get(&'-').is_some_and(|&dash| dash < opts.max_width) && rng.below(4) == 0 {
+                opts.continuation_marker = Some("-".to_string());
+            }
+            if opts.first_line_indent.is_none() && rng.below(4) == 0 {
+                opts.first_line_used_width = rng.below(opts.max_width);
+            }
+            let old = if rng.below(2) == 0 {
+                opts.ansi_escapes = true;
+                font.extend([('\x1b', 1), ('[', 1), ('0', 1), ('1', 1)]);
+                old.replace('q', "\x1b[1m").replace('z', "\x1b[0m")
+            } else {
+                old
+            };
+
+            for _ in 0..rng.below(3) {
+                let (a, b) = (rng.below(old.len() + 1), rng.below(old.len() + 1));
+                opts.no_break_spans.push(a.min(b)..a.max(b));
+            }
+
+            if rng.below(4) == 0 {
+                let lines = count_lines(&old, &opts, &font).unwrap_or(0);
+                opts.max_lines = Some(lines + rng.below(3));
+            }
+
+            let boundaries: Vec<usize> = old
+                .char_indices()
+                .map(|(idx, _)| idx)
+                .chain([old.len()])
+                .filter(|&idx| !old[..idx].ends_with('\r'))
+                .collect();
+            let a = boundaries[rng.below(boundaries.len())];
+            let b = boundaries[rng.below(boundaries.len())];
+            let len = rng.below(8);
+            let replacement: String = rng.text().chars().take(len).collect();
+
+            let _ = check_edit(&old, a.min(b)..a.max(b), &replacement, &opts, &font);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Spreading lines out to fill their width, and lining them up within it.
+
+use std::ops::Range;
+
+use crate::{is_hard_break, LayoutResult};
+
+/// How [`justify`] treats the last line of each paragraph.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LastLine {
+    /// The last line keeps its natural width, leaving the bottom of the paragraph ragged.
+    #[default]
+    Natural,
+    /// The last line is justified like every other line.
+    Always,
+    /// The last line is only justified if it's wider than this fraction of the maximum width,
+    /// so a nearly full line lines up with the rest but a short one isn't stretched.
+    IfOverRatio(f32),
+}
+
+/// Which way the text of a paragraph reads, for [`align`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    /// Lines start on the left, as in English.
+    #[default]
+    LeftToRight,
+    /// Lines start on the right, as in Hebrew or Arabic.
+    RightToLeft,
+}
+
+/// Where [`align`] puts each line across the width, relative to the direction of the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Align {
+    /// Lines start against the side the text reads from.
+    #[default]
+    Start,
+    /// Lines are centered.
+    Center,
+    /// Lines end against the side the text reads towards.
+    End,
+}
+
+/// A run of whitespace between words, and how much wider to draw it, as returned by [`justify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gap {
+    /// The byte range of the whitespace in its line, as returned by [`LayoutResult::lines`].
+    pub range: Range<usize>,
+    /// The width to add to the whitespace.
+    pub extra: usize,
+}
+
+/// Works out how to justify the lines of `result` so each one is `max_width` wide.
+///
+/// Returns the gaps between words on each line, in the same order as [`LayoutResult::lines`].
+/// The room left at the end of a line, after any trailing whitespace is taken off, is shared out
+/// between its gaps as evenly as possible, with the units left over spread out along the line
+/// rather than bunched up at one end. Whitespace
+/// at the start of a line is kept as it is. Lines with no gaps, and lines that are already full,
+/// get nothing added.
+///
+/// The last line of each paragraph, before a hard line break or at the end of the text, is
+/// justified according to `last_line`. Gaps are found in the order of the text, so they come
+/// out the same whichever way it reads, and any trailing whitespace taken off is at the end of
+/// the line in reading order, as it is for [`align`].
+pub fn justify(result: &LayoutResult, max_width: usize, last_line: LastLine) -> Vec<Vec<Gap>> {
+    result
+        .lines()
+        .zip(result.trimmed_line_widths())
+        .enumerate()
+        .map(|(idx, (line, width))| {
+            let ends_paragraph = result.breaks.get(idx).is_none_or(|b| b.mandatory);
+            let justified = !ends_paragraph
+                || match last_line {
+                    LastLine::Natural => false,
+                    LastLine::Always => true,
+                    LastLine::IfOverRatio(ratio) => width as f32 > ratio * max_width as f32,
+                };
+
+            let mut gaps = gaps(line);
+            if justified {
+                let shares = share(max_width.saturating_sub(width), gaps.len());
+                for (gap, extra) in gaps.iter_mut().zip(shares) {
+                    gap.extra = extra;
+                }
+            }
+            gaps
+        })
+        .collect()
+}
+
+/// Works out where to draw each line of `result` so the lines are aligned in `max_width`.
+///
+/// Returns the distance from the left edge of the width to the left edge of each line, in the
+/// same order as [`LayoutResult::lines`]. Lines are measured without the whitespace at their end,
+/// as in [`LayoutResult::trimmed_line_widths`], and the room left over is the padding.
+///
+/// Whitespace at the end of a line is at the end in reading order whichever way the text reads,
+/// so in [`Direction::LeftToRight`] text it's on the right of the line, past its width, and in
+/// [`Direction::RightToLeft`] text it's on the left, before the returned position. Whitespace at
+/// the start of a line is kept, and counts towards its width. The padding goes on the opposite
+/// side to `align`: after the line for [`Align::Start`], which is on the right for left-to-right
+/// text and on the left for right-to-left text, and before it for [`Align::End`]. Centered lines
+/// are mirrored too, so a unit that can't be split goes at the end. A line wider than
+/// `max_width` is at `0` either way, running off the right of the width.
+pub fn align(
+    result: &LayoutResult,
+    max_width: usize,
+    align: Align,
+    direction: Direction,
+) -> Vec<usize> {
+    result
+        .trimmed_line_widths()
+        .map(|width| {
+            let room = max_width.saturating_sub(width);
+            // How far from the start of the line, in reading order, the padding ends
+            let before = match align {
+                Align::Start => 0,
+                Align::Center => room / 2,
+                Align::End => room,
+            };
+            match direction {
+                Direction::LeftToRight => before,
+                Direction::RightToLeft => room - before,
+            }
+        })
+        .collect()
+}
+
+/// Splits `room` into `parts` whole numbers that add up to it, as evenly as possible.
+///
+/// Each part is where the running total of exact fractional shares rounds down to, so the
+/// rounding never drifts by more than one from the exact share at any point along the line.
+fn share(room: usize, parts: usize) -> impl Iterator<Item = usize> {
+    // Only the remainder is spread out, so nothing overflows however much room there is
+    let (each, left_over) = (room.checked_div(parts).unwrap_or(0), room % parts.max(1));
+    (0..parts).map(move |idx| each + (idx + 1) * left_over / parts - idx * left_over / parts)
+}
+
+/// Finds the runs of whitespace between the words of `line`, with nothing extra added yet.
+fn gaps(line: &str) -> Vec<Gap> {
+    let is_space = |c: char| c.is_whitespace() && !is_hard_break(c);
+    let start = line.len() - line.trim_start_matches(is_space).len();
+    let end = line.trim_end_matches(is_space).len();
+
+    let mut gaps: Vec<Gap> = Vec::new();
+    for (offset, c) in line[start..end.max(start)].char_indices() {
+        if !is_space(c) {
+            continue;
+        }
+        let offset = start + offset;
+        match gaps.last_mut() {
+            Some(gap) if gap.range.end == offset => gap.range.end += c.len_utf8(),
+            _ => gaps.push(Gap {
+                range: offset..offset + c.len_utf8(),
+                extra: 0,
+            }),
+        }
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::make_font;
+    use crate::{layout_detailed, LayoutOptions, Overflow};
+    use std::collections::HashMap;
+
+    fn extras(text: &str, max_width: usize, last_line: LastLine) -> Vec<Vec<usize>> {
+        let options = LayoutOptions {
+            overflow: Overflow::Error,
+            ..LayoutOptions::new(max_width)
+        };
+        let result = layout_detailed(text, &options, &make_font()).unwrap();
+        justify(&result, max_width, last_line)
+            .into_iter()
+            .map(|gaps| gaps.into_iter().map(|gap| gap.extra).collect())
+            .collect()
+    }
+
+    #[test]
+    fn gaps_between_words() {
+        assert_eq!(
+            gaps("  ab  c d "),
+            [
+                Gap {
+                    range: 4..6,
+                    extra: 0
+                },
+                Gap {
+                    range: 7..8,
+                    extra: 0
+                }
+            ]
+        );
+        assert_eq!(gaps("abc"), []);
+        assert_eq!(gaps("   "), []);
+    }
+
+    #[test]
+    fn shares_spread_out() {
+        assert_eq!(share(7, 3).collect::<Vec<_>>(), [2, 2, 3]);
+        assert_eq!(share(8, 5).collect::<Vec<_>>(), [1, 2, 1, 2, 2]);
+        assert_eq!(share(6, 3).collect::<Vec<_>>(), [2, 2, 2]);
+        assert_eq!(share(2, 4).collect::<Vec<_>>(), [0, 1, 0, 1]);
+        assert_eq!(share(5, 0).count(), 0);
+        assert_eq!(
+            share(usize::MAX, 2).collect::<Vec<_>>(),
+            [usize::MAX / 2, usize::MAX / 2 + 1]
+        );
+
+        for room in 0..50 {
+            for parts in 1..10 {
+                let shares: Vec<usize> = share(room, parts).collect();
+                assert_eq!(shares.iter().sum::<usize>(), room);
+                assert!(shares.iter().all(|&part| part.abs_diff(room / parts) <= 1));
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_lines() {
+        let hebrew = "שלום עולם יפה";
+        let font: HashMap<char, usize> = hebrew.chars().map(|c| (c, 2)).collect();
+        let result = layout_detailed(hebrew, &LayoutOptions::new(12), &font).unwrap();
+        // "שלום " and "עולם " are 8 wide without their trailing space, "יפה" is 6
+        assert_eq!(result.trimmed_line_widths().collect::<Vec<_>>(), [8, 8, 6]);
+
+        use {Align::*, Direction::*};
+        assert_eq!(align(&result, 12, Start, LeftToRight), [0, 0, 0]);
+        assert_eq!(align(&result, 12, End, LeftToRight), [4, 4, 6]);
+        // Right-to-left lines start on the right, so they're padded on the left
+        assert_eq!(align(&result, 12, Start, RightToLeft), [4, 4, 6]);
+        assert_eq!(align(&result, 12, End, RightToLeft), [0, 0, 0]);
+
+        // An odd unit goes at the end of the line in reading order
+        assert_eq!(align(&result, 13, Center, LeftToRight), [2, 2, 3]);
+        assert_eq!(align(&result, 13, Center, RightToLeft), [3, 3, 4]);
+
+        // Positions can't go past the left edge
+        assert_eq!(align(&result, 4, End, LeftToRight), [0, 0, 0]);
+        assert_eq!(align(&result, 4, End, RightToLeft), [0, 0, 0]);
+    }
+
+    #[test]
+    fn last_lines() {
+        // "aa b cc " is 7 wide without its trailing space, leaving 3 to share out
+        let short = "aa b cc ddd e";
+        let full = "aa b cc ddd eeee";
+
+        assert_eq!(extras(short, 10, LastLine::Natural), [vec![1, 2], vec![0]]);
+        assert_eq!(extras(full, 10, LastLine::Natural), [vec![1, 2], vec![0]]);
+
+        assert_eq!(extras(short, 10, LastLine::Always), [vec![1, 2], vec![5]]);
+        assert_eq!(extras(full, 10, LastLine::Always), [vec![1, 2], vec![2]]);
+
+        // Only a last line over 60% of the width is justified
+        let ratio = LastLine::IfOverRatio(0.6);
+        assert_eq!(extras(short, 10, ratio), [vec![1, 2], vec![0]]);
+        assert_eq!(extras(full, 10, ratio), [vec![1, 2], vec![2]]);
+
+        // The default options have no limit on the width
+        let result = layout_detailed("a b c", &LayoutOptions::default(), &make_font()).unwrap();
+        let gaps = justify(&result, usize::MAX, LastLine::Always);
+        let extra: usize = gaps[0].iter().map(|gap| gap.extra).sum();
+        assert_eq!(extra, usize::MAX - 5);
+
+        // Every paragraph has a last line
+        assert_eq!(
+            extras("a b c\nd e", 4, LastLine::Natural),
+            [vec![1], vec![], vec![0]]
+        );
+    }
+}
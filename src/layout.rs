@@ -0,0 +1,3721 @@
+//! Configurable width-based layout.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::{
+    break_property, is_hard_break, linebreaks, Advance, BreakClass, BreakOpportunity, CachedFont,
+    LineBreakErr,
+};
+
+/// What to do when a run of text has no legal break opportunity and doesn't fit on a line.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Return [`LineBreakErr::NoLegalLinebreakOpportunity`].
+    #[default]
+    Error,
+    /// Break the run at the last character that fits.
+    ///
+    /// This is only a fallback: if there is a legal break opportunity earlier on the line, the
+    /// line breaks there instead, and the run is only broken if it is too wide by itself. So a
+    /// long run after other text, like a URL in a sentence, always moves to a line of its own
+    /// before it is broken.
+    Break,
+    /// Cut the line short and end it with this string, which must fit in the font.
+    ///
+    /// Characters are removed from the end of the line until the ellipsis fits, and the rest of
+    /// the run that didn't fit is dropped, up to its next break opportunity. Wrapping then carries
+    /// on as normal from there. Like [`Overflow::Break`], this only applies when there is no legal
+    /// break opportunity on the line.
+    Ellipsis(String),
+}
+
+/// Which break opportunity a line ends at, when more than one fits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    /// Break at the last opportunity that fits, so each line holds as much text as it can.
+    #[default]
+    Last,
+    /// Break at the opportunity that leaves the line closest to `max_width`, taking the later one
+    /// on a tie.
+    ///
+    /// Without [`LayoutOptions::soft_hyphen`] this is the same as [`Fit::Last`], since a later
+    /// break always makes a line at least as wide. A hyphen added at a soft hyphen can make an
+    /// earlier break wider than a later one, though.
+    Closest,
+}
+
+/// Options controlling how [`layout`] breaks text into lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutOptions {
+    /// The maximum width of a line, in the same units as the font.
+    pub max_width: usize,
+    /// What to do with runs that can't be broken legally.
+    pub overflow: Overflow,
+    /// The maximum number of characters on a line, regardless of their width.
+    ///
+    /// Lines are broken at the last legal opportunity that keeps them within both this and
+    /// `max_width`, falling back to `overflow` when there is none.
+    pub max_chars_per_line: Option<usize>,
+    /// Whether whitespace at the start of a line is kept.
+    pub leading_whitespace: LeadingWhitespace,
+    /// Extra characters that a line may break after, on top of the usual break opportunities.
+    ///
+    /// This lets text like `a,b,c` wrap after each `,` even though it has no spaces. No break is
+    /// added where the next character is whitespace, a combining mark or a joiner, since those
+    /// stay with the character before them.
+    pub break_after: Vec<char>,
+    /// Characters that always stay on the same line as the character before them, such as
+    /// closing punctuation.
+    ///
+    /// The usual break opportunities already keep most punctuation with its word, but still allow
+    /// a break between a space and a closing quote or `…`, and [`Overflow::Break`] can break
+    /// anywhere. With this set, lines never break before these characters, and emergency breaks
+    /// move earlier to keep them with the character they follow. Hard line breaks are unaffected.
+    pub no_break_before: Vec<char>,
+    /// Characters that always stay on the same line as the character after them, such as opening
+    /// brackets and quotes.
+    ///
+    /// The usual break opportunities already keep `(` and `[` with what follows, so this is for
+    /// other characters, like custom quotes, that shouldn't be left at the end of a line. Like
+    /// `no_break_before`, lines never break after these characters, emergency breaks move earlier
+    /// to keep them with the character after them, and hard line breaks are unaffected. It takes
+    /// priority over `break_after`.
+    pub no_break_after: Vec<char>,
+    /// Byte ranges of the input to keep on one line, such as template placeholders like
+    /// `{{user.name}}`, in any order.
+    ///
+    /// Lines never break strictly inside these ranges, but may still break at either end of one.
+    /// A span that doesn't fit is an unbreakable run like any other, so [`LayoutOptions::overflow`]
+    /// decides what happens to it. Hard line breaks inside a span still end the line.
+    pub no_break_spans: Vec<Range<usize>>,
+    /// The most characters in a row that can go without a break opportunity, if there is a
+    /// limit. After that many, an allowed break is added before the next character, so no run is
+    /// ever longer than this.
+    ///
+    /// This keeps text with long runs, like base64 blobs in untrusted input, from failing with
+    /// [`LineBreakErr::NoLegalLinebreakOpportunity`] when the limit fits on a line. Unlike
+    /// [`Overflow::Break`], the breaks come at fixed places in the text rather than wherever a
+    /// line runs out of room. As with `break_after`, no break is added before whitespace, a
+    /// combining mark or a joiner, and `no_break_before`, `no_break_after` and `no_break_spans`
+    /// still apply, so a run can go on past the limit to get to somewhere it may break.
+    pub force_break_every: Option<usize>,
+    /// The line ending to write at the end of every line, instead of keeping the input's.
+    ///
+    /// By default, wrapped lines end with `\n` and hard line breaks are copied from the input
+    /// unchanged, so the output can mix `\n`, `\r\n`, U+2028 and so on. With this set, both are
+    /// written as this string, unless `soft_line_break` is also set. This only changes the output
+    /// text: offsets reported in [`LayoutResult`] are still into the input.
+    pub normalize_line_endings: Option<String>,
+    /// How far short of `max_width` a wrapped line may be, if there is a limit.
+    ///
+    /// A line that is wrapped when it is narrower than `max_width - slack` is reported as
+    /// [`LineBreakErr::UnderfullLine`], which usually means the width is smaller than expected.
+    /// Lines ending in a hard line break, and the last line, may be any width.
+    pub underfill_slack: Option<usize>,
+    /// The string to write where a line is wrapped, so inserted breaks can be told apart from the
+    /// hard line breaks that were already in the input.
+    ///
+    /// This defaults to `normalize_line_endings` if that is set, and `\n` otherwise. Removing
+    /// every occurrence of a marker like `"\u{200B}\n"` from the output gives back the input.
+    pub soft_line_break: Option<String>,
+    /// Text to write at the start of the first line of each paragraph: the first line of the
+    /// text, and each line after a hard line break.
+    ///
+    /// The indent is measured with the font and counts towards the width of the line, so it must
+    /// be narrower than `max_width`, or wrapping fails with [`LineBreakErr::NoAvailableWidth`].
+    /// Empty lines aren't indented. Like the ellipsis, it takes up room in the output but has no
+    /// offset in the input.
+    pub first_line_indent: Option<String>,
+    /// How much of the first line of the text is already taken up, by content drawn before it.
+    ///
+    /// Unlike `first_line_indent`, this writes nothing: the first line just has that much less
+    /// room, for text that carries on from the end of something else before wrapping, and the
+    /// lines after it have the full `max_width`. It applies only to the first line of the text,
+    /// not of each paragraph, and counts towards that line's width, so like an indent, it must
+    /// leave some room, or wrapping fails with [`LineBreakErr::NoAvailableWidth`]. It doesn't
+    /// apply if the first line is empty.
+    pub first_line_used_width: usize,
+    /// The string to write at the end of a line that breaks at a soft hyphen (U+00AD), such as
+    /// `"-"`.
+    ///
+    /// The hyphen is measured with the font and counts towards the width of the line, so a line
+    /// only breaks at a soft hyphen if the hyphen fits too. The soft hyphen itself is measured
+    /// like any other character, and is kept in the output.
+    pub soft_hyphen: Option<String>,
+    /// The fewest characters of a word that can come before a soft hyphen the line breaks at,
+    /// when `soft_hyphen` is set, like TeX's `\lefthyphenmin`.
+    ///
+    /// A word is a run of characters other than whitespace, and the soft hyphens in it aren't
+    /// counted. The text can't break at a soft hyphen too close to the start of its word at all.
+    pub hyphen_min_left: usize,
+    /// The fewest characters of a word that can come after a soft hyphen the line breaks at, like
+    /// `hyphen_min_left` for the end of the word.
+    pub hyphen_min_right: usize,
+    /// Which break opportunity to choose when more than one fits on a line.
+    pub fit: Fit,
+    /// Whether to break at whitespace rather than a later opportunity elsewhere, such as after a
+    /// `/` or `-`.
+    ///
+    /// Lines then break at the opportunity `fit` chooses out of those after whitespace that fit,
+    /// and only if there are none, at the one it chooses out of all of those that fit. This
+    /// leaves some lines shorter than they need to be, but keeps words like paths together.
+    pub prefer_spaces: bool,
+    /// Whether to treat ANSI escape sequences, like the `\x1b[31m` that colors terminal text, as
+    /// having no width.
+    ///
+    /// Each complete CSI sequence (`ESC [`, then parameters, then a final byte from `@` to `~`)
+    /// is kept whole and stays with the visible character after it, so a line never breaks
+    /// inside one or between one and the text it applies to. Break opportunities are found as
+    /// if the escapes weren't there, and the font doesn't need widths for their characters.
+    pub ansi_escapes: bool,
+    /// Whether to fail with [`LineBreakErr::UnexpectedControl`] on control characters, like NUL,
+    /// bell or backspace, other than the hard line breaks.
+    ///
+    /// This covers the C0 and C1 controls, including tab. Escape sequences aren't controls when
+    /// `ansi_escapes` is set. Otherwise, controls are measured like any other character.
+    pub reject_controls: bool,
+    /// Characters that have no width, like markers hidden in the text, so the font doesn't need
+    /// widths for them.
+    ///
+    /// These are glued to the characters on either side of them: the line never breaks right
+    /// before or after one, except at a hard line break.
+    pub zero_width: Vec<char>,
+    /// A string to write at the end of every line that is wrapped, such as `" \\"` for a shell
+    /// script, to mark that it carries on onto the next line.
+    ///
+    /// The marker is measured with the font and counts towards the width of the line, so a line
+    /// only breaks where the marker fits too. Lines that end at a hard line break or the end of
+    /// the text don't get one, and nor do lines cut short by [`Overflow::Ellipsis`]. After a soft
+    /// hyphen, the marker comes after `soft_hyphen`. Like the ellipsis, it takes up room in the
+    /// output but has no offset in the input.
+    pub continuation_marker: Option<String>,
+    /// Whether a line can be exactly `max_width` wide. If not, every line has to be narrower
+    /// than `max_width`, for targets where the last unit is taken up by something else, like a
+    /// border.
+    ///
+    /// This applies everywhere a width is checked against the maximum, including for hyphens,
+    /// ellipses and markers. An indent still only has to be narrower than `max_width`.
+    pub width_inclusive: bool,
+    /// The most lines the text may be wrapped into, if there is a limit. Laying out any more is
+    /// an error, which stops before the output gets any bigger.
+    ///
+    /// This protects against untrusted text that would blow up when wrapped, like a long run
+    /// broken by [`Overflow::Break`] into a line for every character. The empty line after a
+    /// trailing hard line break counts too.
+    pub max_lines: Option<usize>,
+    /// Whether a run of hard line breaks with nothing between them is treated as a single break,
+    /// so `"a\n\n\nb"` is laid out as `"a\nb"`. The first break of the run is the one kept.
+    ///
+    /// This happens after [`LayoutOptions::leading_whitespace`], so a line with only whitespace
+    /// on it is only blank if that whitespace is trimmed off. A blank line at the start of the
+    /// text is kept, since no break comes before it, and so is the empty last line after a
+    /// trailing break. The dropped lines have no line starts, and [`layout_spans`] counts them
+    /// in the whitespace removed before the next line.
+    pub collapse_blank_lines: bool,
+}
+
+impl LayoutOptions {
+    /// Returns the options [`apply_newlines`](crate::apply_newlines) lays text out with, wrapping
+    /// to `max_width`.
+    ///
+    /// Runs that don't fit are an error, wrapped lines end in `\n`, and whitespace is kept. Every
+    /// other option is off, so the text only breaks where the Unicode line breaking rules allow.
+    pub fn new(max_width: usize) -> Self {
+        LayoutOptions {
+            max_width,
+            overflow: Overflow::Error,
+            max_chars_per_line: None,
+            leading_whitespace: LeadingWhitespace::Keep,
+            break_after: Vec::new(),
+            no_break_before: Vec::new(),
+            no_break_after: Vec::new(),
+            no_break_spans: Vec::new(),
+            force_break_every: None,
+            normalize_line_endings: None,
+            underfill_slack: None,
+            soft_line_break: None,
+            first_line_indent: None,
+            first_line_used_width: 0,
+            soft_hyphen: None,
+            hyphen_min_left: 0,
+            hyphen_min_right: 0,
+            fit: Fit::Last,
+            prefer_spaces: false,
+            ansi_escapes: false,
+            reject_controls: false,
+            zero_width: Vec::new(),
+            continuation_marker: None,
+            width_inclusive: true,
+            max_lines: None,
+            collapse_blank_lines: false,
+        }
+    }
+
+    /// The string written where a line is wrapped.
+    fn soft_break_str(&self) -> &str {
+        self.soft_line_break
+            .as_deref()
+            .or(self.normalize_line_endings.as_deref())
+            .unwrap_or("\n")
+    }
+}
+
+/// The same options as [`LayoutOptions::new`], with no maximum width, so nothing is wrapped
+/// unless `max_width` is set.
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+/// A combination of [`LayoutOptions`] that [`validate`] rejects.
+#[derive(Debug, thiserror::Error, PartialEq, Eq, Clone)]
+pub enum OptionError {
+    #[error("max_lines is 0, but all text takes at least one line")]
+    NoLines,
+    #[error("max_chars_per_line is 0, so no character fits on a line")]
+    NoCharsPerLine,
+    #[error("force_break_every is 0, so there would be a break before every character")]
+    ForcedBreakEveryChar,
+    #[error("{option} is empty, so the lines it ends would run together")]
+    EmptyLineBreak {
+        /// The name of the option, `normalize_line_endings` or `soft_line_break`.
+        option: &'static str,
+    },
+    #[error("no_break_spans has the range {start}..{end}, which is backwards")]
+    BackwardsSpan {
+        /// The start of the range.
+        start: usize,
+        /// The end of the range, before its start.
+        end: usize,
+    },
+    #[error("hyphen_min_left or hyphen_min_right is set without soft_hyphen, so it does nothing")]
+    HyphenMinWithoutHyphen,
+}
+
+/// Checks that `opts` is a combination of options that makes sense, before laying anything out.
+///
+/// Every function that lays out text with [`LayoutOptions`] calls this first, and fails with
+/// [`LineBreakErr::InvalidOptions`] if it doesn't pass, so a mistake in setting them up is caught
+/// at the start instead of making odd output. It rejects:
+///
+/// - `max_lines` of `Some(0)`, since even empty text is one line, so nothing could be laid out.
+/// - `max_chars_per_line` of `Some(0)`, since no line could hold anything.
+/// - `force_break_every` of `Some(0)`, which would allow a break before every character. To
+///   break anywhere, use [`Overflow::Break`] or a limit of `1`.
+/// - An empty `normalize_line_endings` or `soft_line_break`, which would join the lines back up
+///   in the output, so it no longer shows where they were broken.
+/// - A range in `no_break_spans` that starts after it ends. Empty ranges are allowed, and do
+///   nothing.
+/// - A nonzero `hyphen_min_left` or `hyphen_min_right` without `soft_hyphen`, since they only
+///   limit breaks at soft hyphens, and those are off.
+///
+/// Options that go further than this, like a `first_line_indent` too wide for `max_width`, are
+/// only found while laying out text, as they depend on the font.
+pub fn validate(opts: &LayoutOptions) -> Result<(), OptionError> {
+    if opts.max_lines == Some(0) {
+        return Err(OptionError::NoLines);
+    }
+    if opts.max_chars_per_line == Some(0) {
+        return Err(OptionError::NoCharsPerLine);
+    }
+    if opts.force_break_every == Some(0) {
+        return Err(OptionError::ForcedBreakEveryChar);
+    }
+    for (option, line_break) in [
+        ("normalize_line_endings", &opts.normalize_line_endings),
+        ("soft_line_break", &opts.soft_line_break),
+    ] {
+        if line_break.as_deref() == Some("") {
+            return Err(OptionError::EmptyLineBreak { option });
+        }
+    }
+    if let Some(span) = opts
+        .no_break_spans
+        .iter()
+        .find(|span| span.start > span.end)
+    {
+        return Err(OptionError::BackwardsSpan {
+            start: span.start,
+            end: span.end,
+        });
+    }
+    if opts.soft_hyphen.is_none() && (opts.hyphen_min_left > 0 || opts.hyphen_min_right > 0) {
+        return Err(OptionError::HyphenMinWithoutHyphen);
+    }
+    Ok(())
+}
+
+/// Which lines have whitespace at their start removed.
+///
+/// Breaks normally fall after whitespace, so it ends up at the end of a line, but a line can
+/// still start with whitespace when an emergency break falls inside a run of spaces. The first
+/// line of the text is never trimmed. Removed whitespace isn't counted towards the width of the
+/// line, and isn't included in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeadingWhitespace {
+    /// Keep all whitespace.
+    #[default]
+    Keep,
+    /// Remove whitespace from the start of lines that were wrapped onto a new line.
+    TrimContinuation,
+    /// Remove whitespace from the start of wrapped lines, and lines after a hard line break.
+    TrimAll,
+}
+
+/// The result of [`layout_detailed`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LayoutResult {
+    /// The text with newlines inserted, exactly as [`layout`] returns it.
+    pub text: String,
+    /// The byte offsets into the input where [`Overflow::Break`] had to break a run that had no
+    /// legal break opportunity, in increasing order.
+    pub emergency_breaks: Vec<usize>,
+    /// The byte offset into the input of the start of each line, in increasing order. The first
+    /// line always starts at `0`. For lines with leading whitespace removed, this is the offset
+    /// after that whitespace.
+    ///
+    /// A hard line break at the end of the input still starts a new, empty line at `input.len()`,
+    /// so there is one line more than there are hard breaks, plus one for each soft wrap.
+    pub line_starts: Vec<usize>,
+    /// Every line break in the output, soft or hard, in order.
+    pub breaks: Vec<ChosenBreak>,
+    /// The byte range of each line in `text`, without the line break after it.
+    line_ranges: Vec<Range<usize>>,
+    line_widths: Vec<usize>,
+    trailing_whitespace: Vec<usize>,
+    line_budgets: Vec<LineBudget>,
+}
+
+impl LayoutResult {
+    /// Empties the result, keeping the memory it has allocated.
+    fn clear(&mut self) {
+        self.text.clear();
+        self.emergency_breaks.clear();
+        self.line_starts.clear();
+        self.breaks.clear();
+        self.line_ranges.clear();
+        self.line_widths.clear();
+        self.trailing_whitespace.clear();
+        self.line_budgets.clear();
+    }
+
+    /// Returns the text of each line, without the line break after it.
+    ///
+    /// Unlike splitting `text` on newlines, this can't be confused by the line break strings, and
+    /// gives the same lines whichever ones were used.
+    pub fn lines(&self) -> impl Iterator<Item = &str> + '_ {
+        self.line_ranges
+            .iter()
+            .map(|range| &self.text[range.clone()])
+    }
+
+    /// Returns the byte range of each line in `text`, without the line break after it.
+    pub(crate) fn line_ranges(&self) -> &[Range<usize>] {
+        &self.line_ranges
+    }
+
+    /// Returns the width of each line, including any ellipsis, in the same order as
+    /// [`LayoutResult::lines`].
+    pub fn line_widths(&self) -> &[usize] {
+        &self.line_widths
+    }
+
+    /// Returns the width of each line without the whitespace at its end, in the same order as
+    /// [`LayoutResult::lines`].
+    ///
+    /// This is how wide the line looks, for aligning it, while [`LayoutResult::line_widths`]
+    /// includes trailing spaces, so it is where a caret at the end of the line goes.
+    pub fn trimmed_line_widths(&self) -> impl Iterator<Item = usize> + '_ {
+        self.line_widths
+            .iter()
+            .zip(&self.trailing_whitespace)
+            .map(|(width, trailing)| width - trailing)
+    }
+
+    /// Returns how much room each line had and how much of it was filled, in the same order as
+    /// [`LayoutResult::lines`].
+    ///
+    /// This is for working out why a line broke where it did when several options change the
+    /// room on it, like [`LayoutOptions::first_line_indent`] or the widths from [`layout_flow`].
+    pub fn line_budgets(&self) -> &[LineBudget] {
+        &self.line_budgets
+    }
+}
+
+/// The room a line had, as returned by [`LayoutResult::line_budgets`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LineBudget {
+    /// The maximum width the line was wrapped to.
+    pub max_width: usize,
+    /// The width taken up before any text, by [`LayoutOptions::first_line_indent`] and
+    /// [`LayoutOptions::first_line_used_width`].
+    pub reserved: usize,
+    /// The width of the line, including `reserved`, the same as in
+    /// [`LayoutResult::line_widths`].
+    pub width: usize,
+}
+
+/// A line break chosen by the layout, as reported in [`LayoutResult::breaks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChosenBreak {
+    /// The byte offset into the input of the break. This is where the next line starts, before
+    /// any leading whitespace is removed from it.
+    pub offset: usize,
+    /// The break class of the character before the break, which is what allowed it: for example
+    /// [`BreakClass::Space`] after a space, or [`BreakClass::LineFeed`] for a hard line break.
+    pub class: BreakClass,
+    /// Whether this was a hard line break from the input.
+    pub mandatory: bool,
+    /// Whether [`Overflow::Break`] had to break a run with no legal break opportunity here.
+    pub emergency: bool,
+}
+
+/// Returns newlines where this text needs it, according to the given options.
+///
+/// Every hard line break already in the input is kept, and each line between them is wrapped
+/// independently, so wrapping one line never moves a break in another.
+pub fn layout(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<String, LineBreakErr> {
+    layout_detailed(input, options, font).map(|result| result.text)
+}
+
+/// Like [`layout`], but also reports how the text was broken.
+pub fn layout_detailed(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<LayoutResult, LineBreakErr> {
+    layout_with(input, options, |_, c| font.get(&c).copied())
+}
+
+/// Like [`layout_detailed`], for options that have already passed [`validate`], so they aren't
+/// checked again.
+pub(crate) fn layout_validated(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<LayoutResult, LineBreakErr> {
+    let lines = Lines::new_validated(input, options, sum_chars(|_, c| font.get(&c).copied()));
+    collect_lines(input, options, lines)
+}
+
+/// Working memory for [`layout_reusing`], kept between calls so laying out many texts doesn't
+/// allocate it again for each one.
+#[derive(Debug, Default)]
+pub struct Scratch {
+    glyphs: Vec<Glyph>,
+    result: LayoutResult,
+}
+
+impl Scratch {
+    /// Creates an empty scratch buffer, which grows to fit the texts laid out with it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Like [`layout`], but keeping its working memory in `scratch`, so laying out many texts, such
+/// as the labels of a UI each frame, only allocates the returned text once the buffer has grown to
+/// fit them.
+///
+/// Whatever `scratch` held before is overwritten, and what it holds afterwards isn't meaningful.
+pub fn layout_reusing(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+    scratch: &mut Scratch,
+) -> Result<String, LineBreakErr> {
+    let width_of = sum_chars(|_, c| font.get(&c).copied());
+    let glyphs = std::mem::take(&mut scratch.glyphs);
+    let mut lines = Lines::with_buffer(input, options, width_of, glyphs);
+    let collected = collect_lines_into(input, options, lines.by_ref(), &mut scratch.result);
+    scratch.glyphs = lines.into_glyphs();
+    collected?;
+
+    // The text has to be handed over, so it is the one buffer that isn't kept
+    Ok(std::mem::take(&mut scratch.result.text))
+}
+
+/// Like [`layout_detailed`], but measuring with an [`Advance`] source instead of a fixed map, so
+/// widths can depend on the character before.
+///
+/// Each combining character sequence, a base character followed by any combining marks and
+/// joiners, is measured as a whole with [`Advance::advance_cluster`], and never broken.
+pub fn layout_advances(
+    input: &str,
+    options: &LayoutOptions,
+    advance: &mut impl Advance,
+) -> Result<LayoutResult, LineBreakErr> {
+    let mut lines = Lines::new(input, options, |_, cluster: &str, prev| {
+        advance
+            .advance_cluster(cluster, prev)
+            .map_err(LineBreakErr::MissingCharacterWidth)
+    });
+
+    let mut after_joiner = false;
+    lines.cluster(input.char_indices().filter_map(|(offset, c)| {
+        // A hard line break is never joined on, even right after a joiner
+        if is_hard_break(c) {
+            after_joiner = false;
+            return Some(offset);
+        }
+        let class = break_property(c as u32);
+        let starts_cluster = !after_joiner
+            && !matches!(
+                class,
+                BreakClass::CombiningMark | BreakClass::ZeroWidthJoiner
+            );
+        after_joiner = class == BreakClass::ZeroWidthJoiner;
+        starts_cluster.then_some(offset)
+    }));
+    collect_lines(input, options, lines)
+}
+
+/// Like [`layout_detailed`], but with text made up of runs that each use a different font.
+///
+/// Each run is a byte range of `input` and the font used to measure the characters in it. Break
+/// opportunities still come from the text as a whole, so runs don't affect where a line may
+/// break, only how wide it is, and the offsets in the result are into `input`, not into any one
+/// run. A character that isn't covered by any run is reported as
+/// [`LineBreakErr::MissingCharacterWidth`].
+///
+/// The runs can be in any order, and are sorted by where they start before anything is measured,
+/// so each character's run is found with a binary search. Runs shouldn't overlap: if they do,
+/// each character is measured with the run that starts last at or before it, or the one that
+/// comes last in `runs` if several start there, and if that run ends before the character, it
+/// isn't covered, even if an earlier run is.
+pub fn layout_runs(
+    input: &str,
+    runs: &[(Range<usize>, &HashMap<char, usize>)],
+    options: &LayoutOptions,
+) -> Result<LayoutResult, LineBreakErr> {
+    let mut runs: Vec<_> = runs.iter().filter(|(range, _)| !range.is_empty()).collect();
+    runs.sort_by_key(|(range, _)| range.start);
+
+    layout_with(input, options, |offset, c| {
+        let idx = runs.partition_point(|(range, _)| range.start <= offset);
+        let (range, font) = runs.get(idx.checked_sub(1)?)?;
+        if !range.contains(&offset) {
+            return None;
+        }
+        font.get(&c).copied()
+    })
+}
+
+/// Like [`layout_detailed`], but measuring with a [`CachedFont`], so ASCII characters are looked
+/// up in its table instead of hashed.
+///
+/// This gives exactly the same result as [`layout_detailed`] with the map the font was built
+/// from, since every character is still measured on its own.
+pub fn layout_cached(
+    input: &str,
+    options: &LayoutOptions,
+    font: &CachedFont,
+) -> Result<LayoutResult, LineBreakErr> {
+    layout_with(input, options, |_, c| font.get(c))
+}
+
+/// Like [`layout_detailed`], but with a few characters measured differently from `base`, such as
+/// a style that only widens some of them, without copying the whole font.
+///
+/// Each character's width is looked up in `overrides` first, then in `base`.
+pub fn layout_with_overrides(
+    input: &str,
+    options: &LayoutOptions,
+    base: &HashMap<char, usize>,
+    overrides: &HashMap<char, usize>,
+) -> Result<LayoutResult, LineBreakErr> {
+    layout_with(input, options, |_, c| {
+        overrides.get(&c).or_else(|| base.get(&c)).copied()
+    })
+}
+
+/// Returns how many lines [`layout`] would break this text into, without building the output.
+///
+/// This is the length of [`LayoutResult::line_starts`], so a trailing hard line break counts the
+/// empty line after it, and empty text is one line.
+#[must_use = "counting lines has no side effects"]
+pub fn count_lines(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<usize, LineBreakErr> {
+    Lines::new(input, options, sum_chars(|_, c| font.get(&c).copied()))
+        .try_fold(0, |count, line| line.map(|_| count + 1))
+}
+
+/// Like [`layout_detailed`], but with the text already split into grapheme clusters.
+///
+/// `boundaries` are the byte offsets where each cluster starts, such as those from a grapheme
+/// segmentation library, in any order. The characters of a cluster are measured together and the
+/// cluster is kept whole: the text is never broken inside it, even by [`Overflow::Break`], and it
+/// counts as one character towards [`LayoutOptions::max_chars_per_line`]. Hard line breaks always
+/// end a cluster, and offsets that aren't character boundaries are ignored.
+///
+/// Without clusters, every character is its own cluster.
+pub fn layout_clusters(
+    input: &str,
+    boundaries: impl IntoIterator<Item = usize>,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<LayoutResult, LineBreakErr> {
+    let mut lines = Lines::new(input, options, sum_chars(|_, c| font.get(&c).copied()));
+    lines.cluster(boundaries);
+    collect_lines(input, options, lines)
+}
+
+/// Like [`layout_detailed`], but only breaking at the opportunities in `breaks`, instead of
+/// where the Unicode line breaking rules allow.
+///
+/// Each opportunity is a byte offset where the line may or must break, before the character
+/// starting there, like those from [`line_break_opportunities`](crate::line_break_opportunities).
+/// The offsets must be character boundaries, in increasing order. The options that add or remove
+/// opportunities, like [`LayoutOptions::break_after`] and [`LayoutOptions::no_break_before`], are
+/// ignored: the text breaks exactly where `breaks` allows, and hard line break characters only
+/// end a line with a [`BreakOpportunity::Mandatory`] before the character after them. They are
+/// still never measured.
+pub fn layout_opportunities(
+    input: &str,
+    breaks: &[(usize, BreakOpportunity)],
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<LayoutResult, LineBreakErr> {
+    debug_assert!(
+        breaks.windows(2).all(|pair| pair[0].0 < pair[1].0),
+        "break opportunities must be in increasing order"
+    );
+    let mut lines = Lines::new(input, options, sum_chars(|_, c| font.get(&c).copied()));
+    lines.set_opportunities(breaks);
+    collect_lines(input, options, lines)
+}
+
+/// The result of [`layout_flow`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Flow {
+    /// The lines that fit, laid out as [`layout_detailed`] would.
+    pub layout: LayoutResult,
+    /// The byte offset of the text that didn't fit, so `&input[remainder..]` can be laid out in
+    /// the next region, or `None` if all of it fit. Whitespace that would have been trimmed off
+    /// the start of the next line is skipped.
+    pub remainder: Option<usize>,
+    /// How far from the left edge of the region each line starts, in the same order as
+    /// [`LayoutResult::lines`]. These are all `0` from [`layout_flow`].
+    pub left_offsets: Vec<usize>,
+}
+
+/// Like [`layout_detailed`], but with a different maximum width for each line, for flowing text
+/// around other content or across pages.
+///
+/// `line_width` is called with the index of each line before it is laid out, and returns its
+/// maximum width, overriding [`LayoutOptions::max_width`]. Returning `None` means there is no
+/// more room, and the rest of the text is left unwrapped in [`Flow::remainder`]. The line break
+/// wrapping the last line that fit onto the next is left out of the text and
+/// [`LayoutResult::breaks`], since that line is in the next region.
+///
+/// Laying out the remainder with another call carries on where this one stopped, so each region
+/// gets the same lines it would have had if the whole text was wrapped at once, as long as those
+/// lines don't depend on being the first line of a paragraph, like
+/// [`LayoutOptions::first_line_indent`].
+pub fn layout_flow(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+    mut line_width: impl FnMut(usize) -> Option<usize>,
+) -> Result<Flow, LineBreakErr> {
+    layout_shaped(input, options, font, |index| {
+        line_width(index).map(|max_width| (0, max_width))
+    })
+}
+
+/// Like [`layout_flow`], but with each line starting at its own distance from the left edge, for
+/// flowing text around shapes on both sides.
+///
+/// `line_box` is called with the index of each line before it is laid out, and returns how far
+/// in from the left edge of the region the line starts, and its maximum width from there. The
+/// offsets are given back in [`Flow::left_offsets`] to draw the lines at, and they don't change
+/// how the text is wrapped, only where it goes. Returning `None` means the shape has no more room,
+/// and the rest of the text is left in [`Flow::remainder`], as for [`layout_flow`].
+///
+/// Lines are still laid out one at a time, each fitting as much as it can before the next one is
+/// asked for, so a line's box can't depend on how the lines after it break. Layouts that look
+/// ahead to even out the lines aren't supported with shaped regions yet.
+pub fn layout_shaped(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+    mut line_box: impl FnMut(usize) -> Option<(usize, usize)>,
+) -> Result<Flow, LineBreakErr> {
+    // Checked before the first line, since the region might not have room for any
+    validate(options).map_err(LineBreakErr::InvalidOptions)?;
+    let mut lines = Lines::new_validated(input, options, sum_chars(|_, c| font.get(&c).copied()));
+    let mut remainder = None;
+    let mut last_end = None;
+    let mut left_offsets = Vec::new();
+
+    let mut index = 0;
+    let flowed = std::iter::from_fn(|| {
+        lines.next_offset()?;
+        let Some((left_offset, max_width)) = line_box(index) else {
+            // The rest starts a fresh layout, which wouldn't trim its first line
+            lines.trim_leading_whitespace();
+            remainder = lines.next_offset();
+            return None;
+        };
+        index += 1;
+
+        left_offsets.push(left_offset);
+        lines.max_width = max_width;
+        let line = lines.next()?;
+        last_end = line.as_ref().ok().map(|line| line.end);
+        Some(line)
+    });
+    let mut layout = collect_lines(input, options, flowed)?;
+
+    if remainder.is_some() && last_end == Some(LineEnd::Soft) {
+        let soft_break = options.soft_break_str();
+        layout.text.truncate(layout.text.len() - soft_break.len());
+        if layout.breaks.pop().is_some_and(|chosen| chosen.emergency) {
+            layout.emergency_breaks.pop();
+        }
+    }
+
+    Ok(Flow {
+        layout,
+        remainder,
+        left_offsets,
+    })
+}
+
+/// Like [`layout_detailed`], but with a different [`Overflow`] policy for each line.
+///
+/// `overflow` is called with the index of each line before it is laid out, and returns what to
+/// do if it has a run that can't be broken, overriding [`LayoutOptions::overflow`]. For example,
+/// a box that fits `n` lines can break long runs on every line but the last, and end that one
+/// with an ellipsis. This doesn't limit the number of lines: the text after line `n - 1` is still
+/// laid out, so use [`layout_flow`] or take the first `n` lines of the result to clamp it.
+pub fn layout_overflow(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+    mut overflow: impl FnMut(usize) -> Overflow,
+) -> Result<LayoutResult, LineBreakErr> {
+    let mut lines = Lines::new(input, options, sum_chars(|_, c| font.get(&c).copied()));
+
+    let mut index = 0;
+    let lines = std::iter::from_fn(|| {
+        lines.next_offset()?;
+        lines.overflow = overflow(index);
+        index += 1;
+        lines.next()
+    });
+    collect_lines(input, options, lines)
+}
+
+/// The result of [`layout_block`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Block {
+    /// The lines, laid out as [`layout_detailed`] would.
+    pub layout: LayoutResult,
+    /// The height of each line, in the same order as [`LayoutResult::lines`].
+    pub line_heights: Vec<usize>,
+    /// The distance from the top of the block to the top of each line, so each is the sum of the
+    /// heights of the lines before it.
+    pub line_tops: Vec<usize>,
+    /// The height of the whole block.
+    pub height: usize,
+}
+
+/// Like [`layout_detailed`], but also stacks the lines vertically.
+///
+/// Each line is as tall as the tallest character drawn on it, including any ellipsis, indent or
+/// hyphen, with heights from `heights` in the same units it uses. `line_height` is the height of
+/// characters that aren't in `heights`, and of empty lines, so without a height map, every line
+/// is `line_height` tall. Heights don't change where lines break.
+pub fn layout_block(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+    heights: Option<&HashMap<char, usize>>,
+    line_height: usize,
+) -> Result<Block, LineBreakErr> {
+    let layout = layout_detailed(input, options, font)?;
+    let line_heights: Vec<usize> = layout
+        .lines()
+        .map(|line| match heights {
+            Some(heights) if !line.is_empty() => line
+                .chars()
+                .map(|c| heights.get(&c).copied().unwrap_or(line_height))
+                .max()
+                .unwrap_or(line_height),
+            _ => line_height,
+        })
+        .collect();
+
+    let mut height = 0;
+    let line_tops = line_heights
+        .iter()
+        .map(|&line| {
+            let top = height;
+            height += line;
+            top
+        })
+        .collect();
+
+    Ok(Block {
+        layout,
+        line_heights,
+        line_tops,
+        height,
+    })
+}
+
+/// A line of the input, as returned by [`layout_spans`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LineSpan {
+    /// The byte range of the input on this line, including the hard line break ending it, if
+    /// any, and any text cut off by [`Overflow::Ellipsis`].
+    pub text_range: Range<usize>,
+    /// The byte range of the whitespace removed from the start of the line by
+    /// [`LayoutOptions::leading_whitespace`], which comes right before `text_range`. This is
+    /// empty when nothing was removed.
+    pub removed_whitespace: Range<usize>,
+}
+
+/// Like [`layout_detailed`], but returns where each line is in the input instead of the output
+/// text.
+///
+/// Every byte of the input is in exactly one span, so concatenating the removed whitespace and
+/// text of each line in order gives back the input. Nothing is written between lines, so
+/// [`LayoutOptions::normalize_line_endings`], [`LayoutOptions::soft_line_break`] and any ellipsis,
+/// indent or hyphen are left for the caller to add.
+pub fn layout_spans(
+    input: &str,
+    options: &LayoutOptions,
+    font: &HashMap<char, usize>,
+) -> Result<Vec<LineSpan>, LineBreakErr> {
+    let mut spans = Vec::new();
+    let mut prev_next = 0;
+
+    for line in Lines::new(input, options, sum_chars(|_, c| font.get(&c).copied())) {
+        let line = line?;
+        spans.push(LineSpan {
+            text_range: line.start..line.next,
+            removed_whitespace: prev_next..line.start,
+        });
+        prev_next = line.next;
+    }
+
+    Ok(spans)
+}
+
+/// The layout algorithm, measuring each character (by byte offset) with `width_of`.
+fn layout_with(
+    input: &str,
+    options: &LayoutOptions,
+    width_of: impl FnMut(usize, char) -> Option<usize>,
+) -> Result<LayoutResult, LineBreakErr> {
+    collect_lines(
+        input,
+        options,
+        Lines::new(input, options, sum_chars(width_of)),
+    )
+}
+
+/// Measures glyphs for [`Lines`] by adding up the widths of their characters, given the width of
+/// each character by its byte offset.
+pub(crate) fn sum_chars(
+    mut width_of: impl FnMut(usize, char) -> Option<usize>,
+) -> impl FnMut(usize, &str, Option<char>) -> Result<usize, LineBreakErr> {
+    move |offset, glyph, _| {
+        glyph.char_indices().try_fold(0, |width, (idx, c)| {
+            let char_width =
+                width_of(offset + idx, c).ok_or(LineBreakErr::MissingCharacterWidth(c))?;
+            Ok(width + char_width)
+        })
+    }
+}
+
+/// Builds the output of a layout from its lines.
+fn collect_lines(
+    input: &str,
+    options: &LayoutOptions,
+    lines: impl Iterator<Item = Result<Line, LineBreakErr>>,
+) -> Result<LayoutResult, LineBreakErr> {
+    let mut result = LayoutResult::default();
+    collect_lines_into(input, options, lines, &mut result)?;
+    Ok(result)
+}
+
+/// Like [`collect_lines`], but into `result`, whose buffers are cleared first and then reused.
+fn collect_lines_into(
+    input: &str,
+    options: &LayoutOptions,
+    lines: impl Iterator<Item = Result<Line, LineBreakErr>>,
+    result: &mut LayoutResult,
+) -> Result<(), LineBreakErr> {
+    result.clear();
+    let line_ending = options.normalize_line_endings.as_deref();
+
+    for line in lines {
+        let line = line?;
+
+        result.line_starts.push(line.start);
+        let line_start = result.text.len();
+        if line.indented {
+            if let Some(indent) = &options.first_line_indent {
+                result.text.push_str(indent);
+            }
+        }
+        let kept = &input[line.start..line.kept];
+        if line.end == LineEnd::Hard {
+            result.text.push_str(kept.trim_end_matches(is_hard_break));
+        } else {
+            result.text.push_str(kept);
+        }
+        if let Some(ellipsis) = &line.ellipsis {
+            result.text.push_str(ellipsis);
+        }
+        if line.hyphenated {
+            if let Some(hyphen) = &options.soft_hyphen {
+                result.text.push_str(hyphen);
+            }
+        }
+        if line.continued {
+            if let Some(marker) = &options.continuation_marker {
+                result.text.push_str(marker);
+            }
+        }
+        result.line_ranges.push(line_start..result.text.len());
+        result.line_widths.push(line.width);
+        result.trailing_whitespace.push(line.trailing_whitespace);
+        result.line_budgets.push(LineBudget {
+            max_width: line.max_width,
+            reserved: line.reserved,
+            width: line.width,
+        });
+
+        if line.end != LineEnd::EndOfText {
+            let before = input[..line.next].chars().next_back();
+            result.breaks.push(ChosenBreak {
+                offset: line.next,
+                class: before.map_or(BreakClass::Unknown, |c| break_property(c as u32)),
+                mandatory: line.end == LineEnd::Hard,
+                emergency: line.emergency,
+            });
+        }
+
+        match (line.end, line_ending) {
+            (LineEnd::Soft, _) => {
+                if line.emergency {
+                    result.emergency_breaks.push(line.next);
+                }
+                result.text.push_str(options.soft_break_str());
+            }
+            (LineEnd::Hard, Some(line_ending)) => result.text.push_str(line_ending),
+            // The hard break is kept, even if an ellipsis cut off the rest of the line
+            (LineEnd::Hard, None) => {
+                let line = &input[line.start..line.next];
+                result
+                    .text
+                    .push_str(&line[line.trim_end_matches(is_hard_break).len()..]);
+            }
+            (LineEnd::EndOfText, _) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns every character of `input` with its byte offset and the break opportunity before it,
+/// including any added by [`LayoutOptions::break_after`] and without any removed by
+/// [`LayoutOptions::no_break_before`].
+///
+/// `escapes` are the escape sequences in `input`, from [`enabled_escapes`].
+pub(crate) fn glyphs<'a>(
+    input: &'a str,
+    options: &'a LayoutOptions,
+    escapes: &'a [Range<usize>],
+) -> impl Iterator<Item = (usize, char, Option<BreakOpportunity>)> + 'a {
+    // Only escaped text needs its opportunities collected first, so plain text doesn't allocate
+    let escaped_breaks = options
+        .ansi_escapes
+        .then(|| escaped_linebreaks(input, escapes));
+    let plain = (!options.ansi_escapes).then(|| linebreaks(input).map(|(_, break_op)| break_op));
+    let breaks = escaped_breaks
+        .into_iter()
+        .flatten()
+        .chain(plain.into_iter().flatten());
+    let in_span = move |offset: usize| {
+        options
+            .no_break_spans
+            .iter()
+            .any(|span| span.start < offset && offset < span.end)
+    };
+
+    let mut prev = None;
+    // The number of visible characters since the last break opportunity, or the start of the text
+    let mut since_break = 0;
+    let mut prev_escaped = false;
+    input
+        .char_indices()
+        .zip(breaks)
+        .map(move |((offset, c), break_op)| {
+            let after_delimiter = prev.is_some_and(|prev| options.break_after.contains(&prev));
+            let glued = options.zero_width.contains(&c)
+                || prev.is_some_and(|prev| {
+                    options.zero_width.contains(&prev) || options.no_break_after.contains(&prev)
+                });
+            prev = Some(c);
+
+            // Escapes take the opportunity of the character after them, so only a glyph's first
+            // character can have one forced before it
+            let escaped = in_escape(escapes, offset);
+            let starts_glyph = !prev_escaped;
+            prev_escaped = escaped;
+            let forced = starts_glyph
+                && options
+                    .force_break_every
+                    .is_some_and(|every| since_break >= every);
+            let break_op = break_op.or_else(|| {
+                use BreakClass::*;
+                let attaches = matches!(
+                    break_property(c as u32),
+                    Space
+                        | Mandatory
+                        | CarriageReturn
+                        | LineFeed
+                        | NextLine
+                        | CombiningMark
+                        | ZeroWidthJoiner
+                        | WordJoiner
+                );
+                ((after_delimiter || forced) && !attaches).then_some(BreakOpportunity::Allowed)
+            });
+            let break_op = break_op.filter(|&break_op| {
+                break_op == BreakOpportunity::Mandatory
+                    || !(glued || options.no_break_before.contains(&c) || in_span(offset))
+            });
+            let visible = usize::from(!escaped);
+            since_break = match break_op {
+                Some(_) => visible,
+                None => since_break + visible,
+            };
+            (offset, c, break_op)
+        })
+}
+
+/// Returns the escape sequences in `input` if [`LayoutOptions::ansi_escapes`] is set, and none
+/// otherwise.
+pub(crate) fn enabled_escapes(input: &str, options: &LayoutOptions) -> Vec<Range<usize>> {
+    if options.ansi_escapes {
+        ansi_escapes(input)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns the byte ranges of the complete ANSI CSI escape sequences in `input`, in order.
+pub(crate) fn ansi_escapes(input: &str) -> Vec<Range<usize>> {
+    let bytes = input.as_bytes();
+    let mut escapes = Vec::new();
+
+    let mut idx = 0;
+    while idx + 1 < bytes.len() {
+        if bytes[idx] != 0x1B || bytes[idx + 1] != b'[' {
+            idx += 1;
+            continue;
+        }
+
+        // Parameter and intermediate bytes, then the final byte
+        let mut end = idx + 2;
+        while end < bytes.len() && (0x20..=0x3F).contains(&bytes[end]) {
+            end += 1;
+        }
+        if end < bytes.len() && (0x40..=0x7E).contains(&bytes[end]) {
+            escapes.push(idx..end + 1);
+            idx = end + 1;
+        } else {
+            idx += 1;
+        }
+    }
+
+    escapes
+}
+
+/// Returns whether the byte at `offset` is inside one of these escape sequences.
+fn in_escape(escapes: &[Range<usize>], offset: usize) -> bool {
+    escapes
+        .get(escapes.partition_point(|escape| escape.end <= offset))
+        .is_some_and(|escape| escape.contains(&offset))
+}
+
+/// Finds the break opportunity before each character of `input`, as if the escape sequences
+/// weren't there. The opportunity before a visible character moves to the start of any escapes
+/// right before it, so they stay together.
+fn escaped_linebreaks(input: &str, escapes: &[Range<usize>]) -> Vec<Option<BreakOpportunity>> {
+    let visible: String = input
+        .char_indices()
+        .filter(|&(offset, _)| !in_escape(escapes, offset))
+        .map(|(_, c)| c)
+        .collect();
+    let mut visible_breaks = linebreaks(&visible).map(|(_, break_op)| break_op);
+
+    let mut breaks = Vec::new();
+    let mut escapes_start = None;
+    let mut prev_visible = None;
+    for (offset, c) in input.char_indices() {
+        if in_escape(escapes, offset) {
+            escapes_start.get_or_insert(breaks.len());
+            breaks.push(None);
+        } else {
+            let break_op = visible_breaks.next().flatten();
+            match escapes_start.take() {
+                Some(start) => {
+                    breaks[start] = break_op;
+                    breaks.push(None);
+                }
+                None => breaks.push(break_op),
+            }
+            prev_visible = Some(c);
+        }
+    }
+
+    // Escapes at the very end stay on the last line, unless a hard break ended it
+    if let Some(start) = escapes_start {
+        if prev_visible.is_some_and(is_hard_break) {
+            breaks[start] = Some(BreakOpportunity::Mandatory);
+        }
+    }
+
+    breaks
+}
+
+/// How a line produced by [`Lines`] ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineEnd {
+    /// The line was wrapped, so a newline has to be inserted.
+    Soft,
+    /// The line ends with a hard line break from the input.
+    Hard,
+    /// The line is the last one.
+    EndOfText,
+}
+
+/// A line produced by [`Lines`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Line {
+    /// The byte offset of the start of the line.
+    pub(crate) start: usize,
+    /// The byte offset where the text kept on this line ends. This includes any hard line break,
+    /// unless the line was cut short by an ellipsis.
+    pub(crate) kept: usize,
+    /// The byte offset of the start of the next line.
+    pub(crate) next: usize,
+    /// The width of the line, including any ellipsis.
+    pub(crate) width: usize,
+    /// How the line ends.
+    pub(crate) end: LineEnd,
+    /// Whether the line was broken by [`Overflow::Break`].
+    pub(crate) emergency: bool,
+    /// The ellipsis the line was cut short with by [`Overflow::Ellipsis`], if it was.
+    pub(crate) ellipsis: Option<String>,
+    /// Whether the line starts with [`LayoutOptions::first_line_indent`].
+    pub(crate) indented: bool,
+    /// Whether the line ends with [`LayoutOptions::soft_hyphen`].
+    pub(crate) hyphenated: bool,
+    /// Whether the line ends with [`LayoutOptions::continuation_marker`].
+    pub(crate) continued: bool,
+    /// The width of the whitespace at the end of the line, which is included in `width`.
+    pub(crate) trailing_whitespace: usize,
+    /// The maximum width the line was wrapped to.
+    pub(crate) max_width: usize,
+    /// The width taken up before the text of the line, which is included in `width`.
+    pub(crate) reserved: usize,
+}
+
+/// A glyph's byte offset, its first character and the break opportunity before it.
+pub(crate) type Glyph = (usize, char, Option<BreakOpportunity>);
+
+/// An iterator over the lines of a layout.
+pub(crate) struct Lines<'a, W> {
+    input: &'a str,
+    options: Cow<'a, LayoutOptions>,
+    width_of: W,
+    /// The escape sequences in the input, if [`LayoutOptions::ansi_escapes`] is set.
+    escapes: Vec<Range<usize>>,
+    /// Every glyph with its byte offset, its first character and the break opportunity before it.
+    /// A glyph is a single character unless the text was clustered.
+    glyphs: Vec<Glyph>,
+    /// The glyph index of the start of the next line.
+    line_start: usize,
+    /// How the previous line ended, if there was one.
+    prev_end: Option<LineEnd>,
+    /// The maximum width of the next line.
+    pub(crate) max_width: usize,
+    /// What to do if the next line has a run that can't be broken.
+    pub(crate) overflow: Overflow,
+    /// The index of the next line.
+    line_index: usize,
+    /// Whether the line being wrapped starts with an indent.
+    indented: bool,
+    /// The width taken up on the line being wrapped before its text.
+    reserved: usize,
+    /// Why the options were rejected by [`validate`], until that's returned as the first line.
+    invalid: Option<OptionError>,
+    done: bool,
+}
+
+impl<'a, W: FnMut(usize, &str, Option<char>) -> Result<usize, LineBreakErr>> Lines<'a, W> {
+    pub(crate) fn new(input: &'a str, options: &'a LayoutOptions, width_of: W) -> Self {
+        Self::with_options(input, Cow::Borrowed(options), width_of, Vec::new(), false)
+    }
+
+    /// Like [`Lines::new`], for options that have already passed [`validate`], so they aren't
+    /// checked again.
+    pub(crate) fn new_validated(input: &'a str, options: &'a LayoutOptions, width_of: W) -> Self {
+        Self::with_options(input, Cow::Borrowed(options), width_of, Vec::new(), true)
+    }
+
+    /// Like [`Lines::new`], but for options that only live as long as the iterator.
+    pub(crate) fn new_owned(input: &'a str, options: LayoutOptions, width_of: W) -> Self {
+        Self::with_options(input, Cow::Owned(options), width_of, Vec::new(), false)
+    }
+
+    /// Like [`Lines::new`], but keeping the glyphs in this buffer, which is cleared first. It can
+    /// be taken back with [`Lines::into_glyphs`] to use again.
+    pub(crate) fn with_buffer(
+        input: &'a str,
+        options: &'a LayoutOptions,
+        width_of: W,
+        buffer: Vec<Glyph>,
+    ) -> Self {
+        Self::with_options(input, Cow::Borrowed(options), width_of, buffer, false)
+    }
+
+    /// Gives back the buffer the glyphs were kept in.
+    pub(crate) fn into_glyphs(self) -> Vec<Glyph> {
+        self.glyphs
+    }
+
+    fn with_options(
+        input: &'a str,
+        options: Cow<'a, LayoutOptions>,
+        width_of: W,
+        mut buffer: Vec<Glyph>,
+        validated: bool,
+    ) -> Self {
+        // The escapes are found once, and kept for measuring
+        let escapes = enabled_escapes(input, &options);
+        buffer.clear();
+        buffer.extend(glyphs(input, &options, &escapes));
+
+        let invalid = if validated {
+            None
+        } else {
+            validate(&options).err()
+        };
+        let mut lines = Lines {
+            input,
+            max_width: options.max_width,
+            overflow: options.overflow.clone(),
+            options,
+            width_of,
+            escapes,
+            glyphs: buffer,
+            line_start: 0,
+            prev_end: None,
+            line_index: 0,
+            indented: false,
+            reserved: 0,
+            invalid,
+            done: false,
+        };
+
+        // Escapes are glued to the visible character after them, unless that is a hard break
+        if !lines.escapes.is_empty() {
+            let mut glued = false;
+            let boundaries: Vec<usize> = input
+                .char_indices()
+                .filter(|&(offset, c)| {
+                    let escaped = in_escape(&lines.escapes, offset);
+                    let starts_escape = escaped && !glued;
+                    let keep = starts_escape || (!escaped && (!glued || is_hard_break(c)));
+                    glued = escaped;
+                    keep
+                })
+                .map(|(offset, _)| offset)
+                .collect();
+            lines.cluster(boundaries);
+        }
+        lines
+    }
+
+    /// Merges the characters between these byte offsets into single glyphs, which are measured
+    /// as a whole and never broken. Hard line break characters are always glyphs of their own,
+    /// so they end the glyph before them and start a new one after them.
+    pub(crate) fn cluster(&mut self, boundaries: impl IntoIterator<Item = usize>) {
+        let mut boundaries: Vec<usize> = boundaries.into_iter().collect();
+        boundaries.sort_unstable();
+
+        let mut first = true;
+        let mut after_hard_break = false;
+        self.glyphs.retain(|&(offset, c, break_op)| {
+            let keep = first
+                || after_hard_break
+                || is_hard_break(c)
+                || break_op == Some(BreakOpportunity::Mandatory)
+                || boundaries.binary_search(&offset).is_ok();
+            first = false;
+            after_hard_break = is_hard_break(c);
+            keep
+        });
+    }
+
+    /// Replaces the break opportunity before each glyph with the one in `breaks` at its offset,
+    /// which must be sorted by offset.
+    pub(crate) fn set_opportunities(&mut self, breaks: &[(usize, BreakOpportunity)]) {
+        for glyph in &mut self.glyphs {
+            glyph.2 = breaks
+                .binary_search_by_key(&glyph.0, |&(offset, _)| offset)
+                .ok()
+                .map(|idx| breaks[idx].1);
+        }
+    }
+
+    /// Starts the next line at this byte offset, which must be a character boundary, as if it
+    /// were line `line_index` and the line before it ended with `prev_end`.
+    pub(crate) fn seek(&mut self, offset: usize, line_index: usize, prev_end: Option<LineEnd>) {
+        self.line_start = self.glyphs.partition_point(|g| g.0 < offset);
+        self.line_index = line_index;
+        self.prev_end = prev_end;
+    }
+
+    /// Returns the byte offset of the start of the next line, if there is one.
+    pub(crate) fn next_offset(&self) -> Option<usize> {
+        (!self.done).then(|| self.offset_of(self.line_start))
+    }
+
+    fn offset_of(&self, idx: usize) -> usize {
+        self.glyphs.get(idx).map_or(self.input.len(), |g| g.0)
+    }
+
+    /// Finds the end of the unbreakable run containing the glyph at `idx`.
+    fn run_end(&self, idx: usize) -> usize {
+        self.glyphs[idx + 1..]
+            .iter()
+            .position(|g| g.2.is_some())
+            .map_or(self.glyphs.len(), |len| idx + 1 + len)
+    }
+
+    /// Measures the glyph at `idx`, which may be made up of several characters, given the
+    /// character before it on the line.
+    fn measure_glyph(&mut self, idx: usize) -> Result<usize, LineBreakErr> {
+        self.measure_glyph_on(idx, self.line_start)
+    }
+
+    /// Like [`Lines::measure_glyph`], on the line starting at the glyph `line_start`.
+    fn measure_glyph_on(&mut self, idx: usize, line_start: usize) -> Result<usize, LineBreakErr> {
+        let input = self.input;
+        let start = self.glyphs[idx].0;
+        let prev = if idx == line_start {
+            None
+        } else {
+            input[..start].chars().next_back()
+        };
+
+        let end = start
+            + input[start..self.offset_of(idx + 1)]
+                .trim_end_matches(is_hard_break)
+                .len();
+        if self.escapes.is_empty() && self.options.zero_width.is_empty() {
+            return (self.width_of)(start, &input[start..end], prev);
+        }
+
+        // Only the text between escapes and zero-width characters is measured
+        let mut width = 0;
+        let mut prev = prev;
+        let mut segment = start;
+        for (offset, c) in input[start..end].char_indices() {
+            let offset = start + offset;
+            if in_escape(&self.escapes, offset) || self.options.zero_width.contains(&c) {
+                if segment < offset {
+                    width += (self.width_of)(segment, &input[segment..offset], prev)?;
+                    prev = input[segment..offset].chars().next_back();
+                }
+                segment = offset + c.len_utf8();
+            }
+        }
+        if segment < end {
+            width += (self.width_of)(segment, &input[segment..end], prev)?;
+        }
+        Ok(width)
+    }
+
+    /// Ends the current line before the glyph at `next`.
+    fn end_line(&mut self, next: usize, width: usize, end: LineEnd) -> Line {
+        let start = self.offset_of(self.line_start);
+        self.line_start = next;
+
+        Line {
+            start,
+            kept: self.offset_of(next),
+            next: self.offset_of(next),
+            width,
+            end,
+            emergency: false,
+            ellipsis: None,
+            indented: self.indented,
+            hyphenated: false,
+            continued: false,
+            trailing_whitespace: 0,
+            max_width: self.max_width,
+            reserved: self.reserved,
+        }
+    }
+
+    /// Whether this line is dropped by [`LayoutOptions::collapse_blank_lines`].
+    fn collapses(&self, line: &Line) -> bool {
+        self.options.collapse_blank_lines
+            && self.prev_end == Some(LineEnd::Hard)
+            && line.end == LineEnd::Hard
+            && self.input[line.start..line.kept].chars().all(is_hard_break)
+    }
+
+    /// Skips the whitespace at the start of the next line, if
+    /// [`LayoutOptions::leading_whitespace`] trims it off.
+    pub(crate) fn trim_leading_whitespace(&mut self) {
+        let trim = matches!(
+            (self.prev_end, self.options.leading_whitespace),
+            (Some(LineEnd::Soft), LeadingWhitespace::TrimContinuation)
+                | (
+                    Some(LineEnd::Soft | LineEnd::Hard),
+                    LeadingWhitespace::TrimAll
+                )
+        );
+        if trim {
+            while let Some(&(_, c, _)) = self.glyphs.get(self.line_start) {
+                if !c.is_whitespace() || is_hard_break(c) {
+                    break;
+                }
+                self.line_start += 1;
+            }
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Line, LineBreakErr> {
+        self.trim_leading_whitespace();
+
+        let line_start = self.line_start;
+        let mut current_width = self.indent_width()?;
+        let mut current_chars = 0;
+        let mut break_point: Option<(usize, usize, bool)> = None;
+        let mut space_break_point: Option<(usize, usize, bool)> = None;
+
+        for cursor in line_start..self.glyphs.len() {
+            let (_, c, break_op) = self.glyphs[cursor];
+
+            // Hard line breaks always end the line, and the next one starts from a fresh state
+            if break_op == Some(BreakOpportunity::Mandatory) && cursor != line_start {
+                return Ok(self.end_line(cursor, current_width, LineEnd::Hard));
+            }
+
+            // The hard line break characters themselves are never measured
+            if is_hard_break(c) {
+                continue;
+            }
+
+            if self.options.reject_controls {
+                self.check_controls(cursor)?;
+            }
+
+            // Add the width of this character
+            let width = self.measure_glyph(cursor)?;
+            current_width += width;
+            current_chars += 1;
+
+            // We weren't over the limit, so we can continue -- but if this is a safe
+            // break point, let's remember that (along with how wide the line is before it)
+            if break_op.is_some() && cursor != line_start {
+                if let Some(candidate) = self.break_candidate(cursor, current_width - width)? {
+                    let fit = self.options.fit;
+                    let better = |best: Option<(usize, usize, bool)>| match fit {
+                        Fit::Last => true,
+                        Fit::Closest => best.is_none_or(|(_, best, _)| candidate.1 >= best),
+                    };
+                    if better(break_point) {
+                        break_point = Some(candidate);
+                    }
+                    let after_space = self.input[..self.glyphs[cursor].0]
+                        .chars()
+                        .next_back()
+                        .is_some_and(char::is_whitespace);
+                    if after_space && better(space_break_point) {
+                        space_break_point = Some(candidate);
+                    }
+                }
+            }
+
+            // Are we over the max width (or character count) now? If so, create a linebreak at
+            // our last safe break point
+            let too_many_chars = self
+                .options
+                .max_chars_per_line
+                .is_some_and(|max_chars| current_chars > max_chars);
+            if self.fits(current_width) && !too_many_chars {
+                continue;
+            }
+
+            let break_point = space_break_point
+                .filter(|_| self.options.prefer_spaces)
+                .or(break_point);
+            match (break_point, &self.overflow) {
+                (Some((break_point, width, hyphenated)), _) => {
+                    let mut line = self.end_line(break_point, width, LineEnd::Soft);
+                    line.hyphenated = hyphenated;
+                    line.continued = self.options.continuation_marker.is_some();
+                    return Ok(line);
+                }
+                (None, Overflow::Error) => {
+                    // Report the whole run, up to where it could have broken
+                    let start = self.offset_of(line_start);
+                    let run = &self.input[start..self.offset_of(self.run_end(cursor))];
+
+                    return Err(LineBreakErr::NoLegalLinebreakOpportunity {
+                        offset: start,
+                        run: run.trim_end().to_string(),
+                    });
+                }
+                // A single glyph wider than the line has to go somewhere, so we keep it
+                (None, Overflow::Break) if cursor == line_start => {}
+                (None, Overflow::Break) => {
+                    // Make room for the continuation marker, as long as something is left
+                    let mut end = cursor;
+                    let mut line_width = current_width - width;
+                    let mut marker = self.marker_width(end)?;
+                    while end > line_start + 1 && !self.fits(line_width + marker) {
+                        end -= 1;
+                        line_width -= self.measure_glyph(end)?;
+                        marker = self.marker_width(end)?;
+                    }
+
+                    // Keep glued characters with the one before them, unless the whole line is
+                    // glued together
+                    let fitted = (end, line_width, marker);
+                    while end > line_start && self.glued_to_previous(end) {
+                        end -= 1;
+                        line_width -= self.measure_glyph(end)?;
+                        marker = self.marker_width(end)?;
+                    }
+                    if end == line_start {
+                        (end, line_width, marker) = fitted;
+                    }
+
+                    let mut line = self.end_line(end, line_width + marker, LineEnd::Soft);
+                    line.emergency = true;
+                    line.continued = self.options.continuation_marker.is_some();
+                    return Ok(line);
+                }
+                (None, Overflow::Ellipsis(ellipsis)) => {
+                    let ellipsis = ellipsis.clone();
+                    return self.ellipsize(cursor, current_width - width, &ellipsis);
+                }
+            }
+        }
+
+        // A hard line break at the very end still ends its line, and the empty line after it is
+        // the last one
+        if line_start < self.glyphs.len() && self.ends_with_hard_break() {
+            return Ok(self.end_line(self.glyphs.len(), current_width, LineEnd::Hard));
+        }
+
+        self.done = true;
+        Ok(self.end_line(self.glyphs.len(), current_width, LineEnd::EndOfText))
+    }
+
+    /// Checks whether the line could break before the glyph at `cursor`, given the width of the
+    /// line up to it. Returns the width the line would be and whether it would be hyphenated, or
+    /// `None` if a hyphen or continuation marker wouldn't fit.
+    fn break_candidate(
+        &mut self,
+        cursor: usize,
+        width: usize,
+    ) -> Result<Option<(usize, usize, bool)>, LineBreakErr> {
+        let offset = self.offset_of(cursor);
+        let mut width = width;
+        let mut prev = self.input[..offset].chars().next_back();
+
+        let hyphen = self
+            .options
+            .soft_hyphen
+            .as_deref()
+            .filter(|_| prev == Some('\u{AD}'));
+        if hyphen.is_some() && !self.hyphen_fits_word(offset) {
+            return Ok(None);
+        }
+        if let Some(hyphen) = hyphen {
+            width += (self.width_of)(offset, hyphen, prev)?;
+            prev = hyphen.chars().next_back();
+        }
+        let marker = self.options.continuation_marker.as_deref();
+        if let Some(marker) = marker {
+            width += (self.width_of)(offset, marker, prev)?;
+        }
+
+        let fits = (hyphen.is_none() && marker.is_none()) || self.fits(width);
+        Ok(fits.then_some((cursor, width, hyphen.is_some())))
+    }
+
+    /// Returns whether a line `width` wide fits in the maximum width.
+    fn fits(&self, width: usize) -> bool {
+        if self.options.width_inclusive {
+            width <= self.max_width
+        } else {
+            width < self.max_width
+        }
+    }
+
+    /// Returns whether the glyph at `idx` has to stay on the same line as the one before it.
+    fn glued_to_previous(&self, idx: usize) -> bool {
+        let before = self.input[..self.offset_of(idx)].chars().next_back();
+        self.options.no_break_before.contains(&self.glyphs[idx].1)
+            || before.is_some_and(|c| self.options.no_break_after.contains(&c))
+    }
+
+    /// Checks that a break at the soft hyphen before `offset` leaves enough of its word on either
+    /// side of it.
+    fn hyphen_fits_word(&self, offset: usize) -> bool {
+        let word_chars = |chars: &mut dyn Iterator<Item = char>| {
+            chars
+                .take_while(|c| !c.is_whitespace())
+                .filter(|&c| c != '\u{AD}')
+                .count()
+        };
+        word_chars(&mut self.input[..offset].chars().rev()) >= self.options.hyphen_min_left
+            && word_chars(&mut self.input[offset..].chars()) >= self.options.hyphen_min_right
+    }
+
+    /// Measures [`LayoutOptions::continuation_marker`] at the end of a line ending before the
+    /// glyph at `next`.
+    fn marker_width(&mut self, next: usize) -> Result<usize, LineBreakErr> {
+        let Some(marker) = self.options.continuation_marker.as_deref() else {
+            return Ok(0);
+        };
+        let offset = self.offset_of(next);
+        let prev = self.input[..offset].chars().next_back();
+        (self.width_of)(offset, marker, prev)
+    }
+
+    /// Fails if the glyph at `cursor` contains a control character outside of an escape sequence.
+    fn check_controls(&self, cursor: usize) -> Result<(), LineBreakErr> {
+        let start = self.glyphs[cursor].0;
+        let glyph = &self.input[start..self.offset_of(cursor + 1)];
+        let control = glyph.char_indices().find(|&(offset, c)| {
+            c.is_control() && !is_hard_break(c) && !in_escape(&self.escapes, start + offset)
+        });
+        match control {
+            Some((offset, ch)) => Err(LineBreakErr::UnexpectedControl {
+                ch,
+                offset: start + offset,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Measures the whitespace at the end of `line`, which is part of its width.
+    fn trailing_whitespace(&mut self, line: &Line) -> Result<usize, LineBreakErr> {
+        if line.ellipsis.is_some() || line.hyphenated || line.continued {
+            return Ok(0);
+        }
+
+        let first = self.glyphs.partition_point(|g| g.0 < line.start);
+        let mut idx = self.glyphs.partition_point(|g| g.0 < line.kept);
+
+        let mut width = 0;
+        while idx > first {
+            idx -= 1;
+            let c = self.glyphs[idx].1;
+            if is_hard_break(c) {
+                continue;
+            }
+            if !c.is_whitespace() {
+                break;
+            }
+            width += self.measure_glyph_on(idx, first)?;
+        }
+        Ok(width)
+    }
+
+    /// Decides whether the next line is indented, and returns the width taken up before its
+    /// text, by the indent and by anything already on the first line.
+    fn indent_width(&mut self) -> Result<usize, LineBreakErr> {
+        let starts_paragraph = matches!(self.prev_end, None | Some(LineEnd::Hard));
+        let empty = self
+            .glyphs
+            .get(self.line_start)
+            .is_none_or(|&(_, c, _)| is_hard_break(c));
+
+        self.indented = false;
+        self.reserved = 0;
+        if empty {
+            return Ok(0);
+        }
+
+        let mut width = match self.prev_end {
+            None => self.options.first_line_used_width,
+            Some(_) => 0,
+        };
+        if let Some(indent) = self
+            .options
+            .first_line_indent
+            .as_deref()
+            .filter(|_| starts_paragraph)
+        {
+            let offset = self.offset_of(self.line_start);
+            width += (self.width_of)(offset, indent, None)?;
+            self.indented = true;
+        }
+        if (self.indented || width > 0) && width >= self.max_width {
+            return Err(LineBreakErr::NoAvailableWidth {
+                max_width: self.max_width,
+                reserved: width,
+            });
+        }
+        self.reserved = width;
+        Ok(width)
+    }
+
+    fn ends_with_hard_break(&self) -> bool {
+        self.glyphs
+            .last()
+            .is_some_and(|&(_, c, _)| is_hard_break(c))
+    }
+
+    /// Cuts the current line short with an ellipsis, given the glyph that didn't fit on it and
+    /// the width of the line before that glyph.
+    fn ellipsize(
+        &mut self,
+        cursor: usize,
+        mut width: usize,
+        ellipsis: &str,
+    ) -> Result<Line, LineBreakErr> {
+        let offset = self.glyphs[cursor].0;
+        let mut ellipsis_width = 0;
+        let mut prev = None;
+        for (idx, c) in ellipsis.char_indices() {
+            ellipsis_width += (self.width_of)(offset, &ellipsis[idx..idx + c.len_utf8()], prev)?;
+            prev = Some(c);
+        }
+        let ellipsis_chars = ellipsis.chars().count();
+
+        // Trim the end of the line until the ellipsis fits after it
+        let mut end = cursor;
+        while end > self.line_start
+            && (!self.fits(width + ellipsis_width)
+                || self
+                    .options
+                    .max_chars_per_line
+                    .is_some_and(|max_chars| end - self.line_start + ellipsis_chars > max_chars))
+        {
+            end -= 1;
+            width -= self.measure_glyph(end)?;
+        }
+
+        // The rest of the run is dropped, but a hard break ending it is kept
+        let resume = self.run_end(cursor);
+        let line_end = match self.glyphs.get(resume) {
+            Some(&(_, _, Some(BreakOpportunity::Mandatory))) => LineEnd::Hard,
+            Some(_) => LineEnd::Soft,
+            None if self.ends_with_hard_break() => LineEnd::Hard,
+            None => {
+                self.done = true;
+                LineEnd::EndOfText
+            }
+        };
+
+        let mut line = self.end_line(resume, width + ellipsis_width, line_end);
+        line.kept = self.offset_of(end);
+        line.ellipsis = Some(ellipsis.to_string());
+        Ok(line)
+    }
+}
+
+impl<W: FnMut(usize, &str, Option<char>) -> Result<usize, LineBreakErr>> Iterator for Lines<'_, W> {
+    type Item = Result<Line, LineBreakErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some(err) = self.invalid.take() {
+            self.done = true;
+            return Some(Err(LineBreakErr::InvalidOptions(err)));
+        }
+
+        loop {
+            if let Some(limit) = self
+                .options
+                .max_lines
+                .filter(|&limit| self.line_index >= limit)
+            {
+                self.done = true;
+                return Some(Err(LineBreakErr::TooManyLines { limit }));
+            }
+
+            let start = self.line_start;
+            let line = self.next_line().and_then(|mut line| {
+                line.trailing_whitespace = self.trailing_whitespace(&line)?;
+
+                let min_width = self
+                    .options
+                    .underfill_slack
+                    .map_or(0, |slack| self.max_width.saturating_sub(slack));
+                if line.end == LineEnd::Soft && line.width < min_width {
+                    return Err(LineBreakErr::UnderfullLine {
+                        line: self.line_index,
+                        width: line.width,
+                    });
+                }
+                Ok(line)
+            });
+
+            // Every line but the last has to move on, or wrapping would never finish
+            if line.is_ok() && !self.done && self.line_start <= start {
+                debug_assert!(
+                    self.line_start > start,
+                    "the line starting at glyph {start} didn't move on"
+                );
+                self.done = true;
+                return Some(Err(LineBreakErr::Stalled {
+                    offset: self.offset_of(start),
+                }));
+            }
+
+            match &line {
+                // A blank line right after a hard break is dropped, with the break ending it
+                Ok(line) if self.collapses(line) => continue,
+                Ok(line) => {
+                    self.prev_end = Some(line.end);
+                    self.line_index += 1;
+                }
+                Err(_) => self.done = true,
+            }
+            return Some(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{make_font, Rng};
+    use crate::{
+        line_break_opportunities, min_unbreakable_width, monospace_font, width_prefix_sums,
+    };
+
+    fn options(max_width: usize, overflow: Overflow) -> LayoutOptions {
+        LayoutOptions {
+            overflow,
+            ..LayoutOptions::new(max_width)
+        }
+    }
+
+    #[test]
+    fn overflow_error() {
+        assert_eq!(
+            layout(
+                "Supercalifragalisticexpialidocious",
+                &options(30, Overflow::Error),
+                &make_font()
+            )
+            .unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "Supercalifragalisticexpialidocious".to_string()
+            }
+        );
+
+        assert_eq!(
+            layout(
+                "a b\nok Supercalifragalistic yes",
+                &options(10, Overflow::Error),
+                &make_font()
+            )
+            .unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 7,
+                run: "Supercalifragalistic".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn overflow_break() {
+        let opts = options(10, Overflow::Break);
+
+        assert_eq!(
+            layout("Supercalifragalistic", &opts, &make_font()).unwrap(),
+            "Supercalif\nragalistic"
+        );
+
+        // The space before the token is a real opportunity, so it wins over chopping the token
+        assert_eq!(
+            layout("ok reallyreallylongunbreakabletoken", &opts, &make_font()).unwrap(),
+            "ok \nreallyreal\nlylongunbr\neakabletok\nen"
+        );
+        assert_eq!(
+            layout("hi verylongunbreakabletoken", &opts, &make_font()).unwrap(),
+            "hi \nverylongun\nbreakablet\noken"
+        );
+
+        // A glyph wider than the line still makes progress
+        let mut font = make_font();
+        font.insert('W', 20);
+        assert_eq!(layout("aWb", &opts, &font).unwrap(), "a\nW\nb");
+    }
+
+    #[test]
+    fn max_chars_per_line() {
+        let mut font = make_font();
+        font.insert('W', 3);
+        let mut opts = options(100, Overflow::Error);
+        opts.max_chars_per_line = Some(5);
+
+        // Only the character limit is binding
+        assert_eq!(
+            layout("abc def ghi", &opts, &font).unwrap(),
+            "abc \ndef \nghi"
+        );
+        assert_eq!(
+            layout("abcdefghi", &opts, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "abcdefghi".to_string()
+            }
+        );
+
+        opts.overflow = Overflow::Break;
+        assert_eq!(layout("abcdefghi", &opts, &font).unwrap(), "abcde\nfghi");
+
+        // Both are binding: "WW WW" is only 5 characters, but too wide
+        opts.max_width = 9;
+        assert_eq!(
+            layout("WW WW a bc", &opts, &font).unwrap(),
+            "WW \nWW a \nbc"
+        );
+        assert_eq!(layout("WWWWW", &opts, &font).unwrap(), "WWW\nWW");
+    }
+
+    #[test]
+    fn preserves_hard_breaks() {
+        let opts = options(8, Overflow::Break);
+
+        // Break opportunities from a previous line are never reused
+        assert_eq!(
+            layout("aaaa bbbb\ncccccccccc", &opts, &make_font()).unwrap(),
+            "aaaa \nbbbb\ncccccccc\ncc"
+        );
+        assert_eq!(
+            layout("aa bb cc\ndd ee ff gg", &opts, &make_font()).unwrap(),
+            "aa bb cc\ndd ee \nff gg"
+        );
+
+        // Other hard breaks behave the same way
+        assert_eq!(
+            layout("aaaa\r\nbbbb\u{2028}cc dd ee ff", &opts, &make_font()).unwrap(),
+            "aaaa\r\nbbbb\u{2028}cc dd \nee ff"
+        );
+        assert_eq!(
+            layout("\n\naaaa bbbb\n\n", &opts, &make_font()).unwrap(),
+            "\n\naaaa \nbbbb\n\n"
+        );
+
+        // A hard break right where the line fills up doesn't add an extra one
+        assert_eq!(
+            layout("aaaaaaaa\nbbbbbbbb", &opts, &make_font()).unwrap(),
+            "aaaaaaaa\nbbbbbbbb"
+        );
+    }
+
+    #[test]
+    fn trailing_hard_break_starts_a_line() {
+        let opts = options(8, Overflow::Break);
+        let line_starts = |text| {
+            layout_detailed(text, &opts, &make_font())
+                .unwrap()
+                .line_starts
+        };
+
+        // Every hard break starts a new line, even at the end of the text, so there is always
+        // one more line than there are hard breaks, plus one for each soft wrap
+        assert_eq!(line_starts(""), [0]);
+        assert_eq!(line_starts("a"), [0]);
+        assert_eq!(line_starts("a\n"), [0, 2]);
+        assert_eq!(line_starts("a\nb"), [0, 2]);
+        assert_eq!(line_starts("a\n\n"), [0, 2, 3]);
+        assert_eq!(line_starts("\r\n"), [0, 2]);
+        assert_eq!(line_starts("aaaa bbbb\n"), [0, 5, 10]);
+
+        // The output text is unchanged by the empty last line
+        assert_eq!(layout("a\n\n", &opts, &make_font()).unwrap(), "a\n\n");
+
+        // Even when an ellipsis cuts off the run ending in the hard break
+        let mut font = make_font();
+        font.insert('…', 1);
+        let opts = options(4, Overflow::Ellipsis("…".to_string()));
+        let result = layout_detailed("aaaaaa\n", &opts, &font).unwrap();
+        assert_eq!(result.text, "aaa…\n");
+        assert_eq!(result.line_starts, [0, 7]);
+    }
+
+    #[test]
+    fn styled_runs() {
+        let font = make_font();
+        let wide: HashMap<char, usize> = font.keys().map(|&c| (c, 2)).collect();
+        let opts = options(10, Overflow::Error);
+
+        // "big" is twice as wide, so less fits on the first line
+        let text = "aa big cc dd";
+        let result =
+            layout_runs(text, &[(0..3, &font), (3..6, &wide), (6..12, &font)], &opts).unwrap();
+        assert_eq!(result.text, "aa big \ncc dd");
+        assert_eq!(result.line_starts, [0, 7]);
+        assert_eq!(result.breaks.len(), 1);
+        assert_eq!(result.breaks[0].offset, 7);
+        assert_eq!(
+            layout_runs(text, &[(0..12, &font)], &opts).unwrap(),
+            layout_detailed(text, &opts, &font).unwrap()
+        );
+
+        // Characters outside every run can't be measured
+        assert_eq!(
+            layout_runs(text, &[(0..3, &font)], &opts).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('b')
+        );
+
+        // Runs can come in any order, and empty ones are skipped
+        let empty = 3..3;
+        assert_eq!(
+            layout_runs(
+                text,
+                &[(6..12, &font), (3..6, &wide), (empty, &font), (0..3, &font)],
+                &opts
+            )
+            .unwrap()
+            .text,
+            "aa big \ncc dd"
+        );
+
+        // A run for every character, backwards
+        let fonts = [&font, &wide];
+        let many: Vec<_> = (0..text.len())
+            .map(|offset| (offset..offset + 1, fonts[(offset / 3) % 2]))
+            .rev()
+            .collect();
+        assert_eq!(
+            layout_runs(text, &many, &opts).unwrap().text,
+            "aa big \ncc dd"
+        );
+    }
+
+    #[test]
+    fn overridden_widths() {
+        let font = make_font();
+        let opts = options(6, Overflow::Error);
+        let text = "aa bb cc";
+        assert_eq!(
+            layout_with_overrides(text, &opts, &font, &HashMap::new()),
+            layout_detailed(text, &opts, &font)
+        );
+
+        // A wider b pushes "bb" onto the next line
+        assert_eq!(layout(text, &opts, &font).unwrap(), "aa bb \ncc");
+        let bold = HashMap::from([('b', 2)]);
+        let result = layout_with_overrides(text, &opts, &font, &bold).unwrap();
+        assert_eq!(result.text, "aa \nbb \ncc");
+        assert_eq!(result.line_widths(), [3, 5, 2]);
+
+        // Overrides can cover what the base font doesn't
+        let mut base = make_font();
+        base.remove(&'c');
+        assert_eq!(
+            layout_with_overrides(text, &opts, &base, &bold).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('c')
+        );
+        let both = HashMap::from([('b', 2), ('c', 1)]);
+        assert!(layout_with_overrides(text, &opts, &base, &both).is_ok());
+    }
+
+    #[test]
+    fn lines_and_widths() {
+        let mut font = make_font();
+        font.insert('W', 3);
+        font.insert('…', 1);
+        let mut opts = options(6, Overflow::Ellipsis("…".to_string()));
+        opts.soft_line_break = Some("\n\n".to_string());
+
+        let result = layout_detailed("aa W\n\nbbbbbbbb\r\ncc dd", &opts, &font).unwrap();
+        assert_eq!(result.text, "aa W\n\nbbbbb…\r\ncc dd");
+        assert_eq!(
+            result.lines().collect::<Vec<_>>(),
+            ["aa W", "", "bbbbb…", "cc dd"]
+        );
+        assert_eq!(result.line_widths(), [6, 0, 6, 5]);
+
+        let result = layout_detailed("aa bb cc dd", &opts, &font).unwrap();
+        assert_eq!(result.text, "aa bb \n\ncc dd");
+        assert_eq!(result.lines().collect::<Vec<_>>(), ["aa bb ", "cc dd"]);
+        assert_eq!(result.line_widths(), [6, 5]);
+    }
+
+    #[test]
+    fn trailing_whitespace_widths() {
+        let mut font = make_font();
+        font.insert('\u{3000}', 2);
+        let opts = options(6, Overflow::Break);
+
+        // Several spaces at the end of a wrapped line
+        let result = layout_detailed("aaa   bbb", &opts, &font).unwrap();
+        assert_eq!(result.text, "aaa   \nbbb");
+        assert_eq!(result.line_widths(), [6, 3]);
+        assert_eq!(result.trimmed_line_widths().collect::<Vec<_>>(), [3, 3]);
+
+        // Before a hard break, and lines made only of whitespace
+        let result = layout_detailed("a \u{3000}\r\n   \nb ", &opts, &font).unwrap();
+        assert_eq!(result.line_widths(), [4, 3, 2]);
+        assert_eq!(result.trimmed_line_widths().collect::<Vec<_>>(), [1, 0, 1]);
+    }
+
+    #[test]
+    fn reports_chosen_breaks() {
+        let opts = options(6, Overflow::Break);
+        let result = layout_detailed("aa bb-cc\r\ndddddddd", &opts, &make_font()).unwrap();
+        assert_eq!(result.text, "aa bb-\ncc\r\ndddddd\ndd");
+
+        let breaks: Vec<_> = result
+            .breaks
+            .iter()
+            .map(|b| (b.offset, b.class, b.mandatory, b.emergency))
+            .collect();
+        assert_eq!(
+            breaks,
+            [
+                (6, BreakClass::Hyphen, false, false),
+                (10, BreakClass::LineFeed, true, false),
+                (16, BreakClass::Alphabetic, false, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_emergency_breaks() {
+        let opts = options(10, Overflow::Break);
+
+        let result = layout_detailed("ok reallyreallylongtoken", &opts, &make_font()).unwrap();
+        assert_eq!(result.text, "ok \nreallyreal\nlylongtoke\nn");
+        assert_eq!(result.emergency_breaks, [13, 23]);
+
+        // Wrapping nicely needs no emergency breaks
+        let result = layout_detailed("some words that wrap", &opts, &make_font()).unwrap();
+        assert_eq!(result.emergency_breaks, []);
+    }
+
+    #[test]
+    fn counts_lines() {
+        let opts = options(4, Overflow::Break);
+        let font = make_font();
+
+        assert_eq!(count_lines("", &opts, &font).unwrap(), 1);
+        assert_eq!(count_lines("aa bb cc", &opts, &font).unwrap(), 3);
+        assert_eq!(count_lines("aa\r\nbbbbbb\n", &opts, &font).unwrap(), 4);
+        assert_eq!(
+            count_lines("aaaaaa", &options(4, Overflow::Error), &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "aaaaaa".to_string()
+            }
+        );
+
+        for text in ["aa bb\ncc dd ee", "a\u{2028}b\r\n\n"] {
+            let result = layout_detailed(text, &opts, &font).unwrap();
+            assert_eq!(
+                count_lines(text, &opts, &font).unwrap(),
+                result.line_starts.len()
+            );
+        }
+    }
+
+    #[test]
+    fn lines_fit_unless_forced() {
+        for seed in 0..2000 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let max_width = 1 + rng.below(20);
+
+            for overflow in [Overflow::Error, Overflow::Break] {
+                let opts = options(max_width, overflow.clone());
+                let output = layout(&text, &opts, &font);
+                if overflow == Overflow::Error {
+                    // Wrapping only fails when some run is too wide by itself
+                    let min_width = min_unbreakable_width(&text, &font).unwrap();
+                    assert_eq!(output.is_ok(), max_width >= min_width, "seed {}", seed);
+                }
+                let Ok(output) = output else {
+                    continue;
+                };
+
+                for line in output.split('\n') {
+                    let width = *width_prefix_sums(line, &font).unwrap().last().unwrap();
+                    let glyphs = line.chars().filter(|&c| !is_hard_break(c)).count();
+
+                    // Only a single glyph that is too wide by itself may overflow
+                    assert!(
+                        width <= max_width || (overflow == Overflow::Break && glyphs == 1),
+                        "seed {}: {:?} is {} wide in {:?} at {}",
+                        seed,
+                        line,
+                        width,
+                        output,
+                        max_width,
+                    );
+                }
+
+                // And the trimmed widths leave out exactly the trailing whitespace
+                let result = layout_detailed(&text, &opts, &font).unwrap();
+                for (line, trimmed) in result.lines().zip(result.trimmed_line_widths()) {
+                    let line = line.trim_end_matches(char::is_whitespace);
+                    let width = *width_prefix_sums(line, &font).unwrap().last().unwrap();
+                    assert_eq!(trimmed, width, "seed {}: {:?}", seed, line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn breaks_are_opportunities() {
+        for seed in 0..2000 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let mut opts = options(
+                1 + rng.below(20),
+                [Overflow::Error, Overflow::Break][rng.below(2)].clone(),
+            );
+            opts.leading_whitespace = [
+                LeadingWhitespace::Keep,
+                LeadingWhitespace::TrimContinuation,
+                LeadingWhitespace::TrimAll,
+            ][rng.below(3)];
+            if rng.below(2) == 0 {
+                opts.soft_hyphen = Some("-".to_string());
+            }
+            opts.fit = [Fit::Last, Fit::Closest][rng.below(2)];
+            opts.prefer_spaces = rng.below(2) == 0;
+
+            let Ok(result) = layout_detailed(&text, &opts, &font) else {
+                continue;
+            };
+            let opportunities: HashMap<usize, BreakOpportunity> = linebreaks(&text)
+                .filter_map(|(offset, break_op)| Some((offset, break_op?)))
+                .collect();
+
+            // Every break but the forced ones is somewhere the text could break, and hard line
+            // breaks are exactly the mandatory ones
+            for b in result.breaks.iter().filter(|b| !b.emergency) {
+                let break_op = opportunities.get(&b.offset);
+                assert!(break_op.is_some(), "seed {}: {:?} in {:?}", seed, b, text);
+                assert_eq!(
+                    b.mandatory,
+                    break_op == Some(&BreakOpportunity::Mandatory),
+                    "seed {}: {:?} in {:?}",
+                    seed,
+                    b,
+                    text
+                );
+            }
+            // The end of the text is only a break if it follows a hard line break
+            let ends_with_break = text.ends_with(is_hard_break);
+            let mut hard: Vec<usize> = opportunities
+                .iter()
+                .filter(|&(&offset, &break_op)| {
+                    break_op == BreakOpportunity::Mandatory
+                        && (offset < text.len() || ends_with_break)
+                })
+                .map(|(&offset, _)| offset)
+                .collect();
+            hard.sort_unstable();
+            assert_eq!(
+                result
+                    .breaks
+                    .iter()
+                    .filter(|b| b.mandatory)
+                    .map(|b| b.offset)
+                    .collect::<Vec<_>>(),
+                hard,
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn overflow_ellipsis() {
+        let mut font = make_font();
+        font.insert('…', 1);
+        let opts = options(8, Overflow::Ellipsis("…".to_string()));
+
+        assert_eq!(
+            layout("Supercalifragalistic", &opts, &font).unwrap(),
+            "Superca…"
+        );
+
+        // The rest of the token is dropped, and wrapping continues after it
+        assert_eq!(
+            layout("ok reallyreallylongtoken and more", &opts, &font).unwrap(),
+            "ok \nreallyr…\nand more"
+        );
+        assert_eq!(
+            layout("reallyreallylongtoken\nnext", &opts, &font).unwrap(),
+            "reallyr…\nnext"
+        );
+
+        // Wider ellipses trim more
+        let opts = options(8, Overflow::Ellipsis("...".to_string()));
+        assert_eq!(
+            layout("Supercalifragalistic", &opts, &font).unwrap(),
+            "Super..."
+        );
+
+        let opts = options(8, Overflow::Ellipsis("≤".to_string()));
+        assert_eq!(
+            layout("Supercalifragalistic", &opts, &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+    }
+
+    #[test]
+    fn leading_whitespace() {
+        let mut opts = options(5, Overflow::Break);
+
+        // The double space straddles the emergency break
+        assert_eq!(
+            layout("aaaa  bbbb", &opts, &make_font()).unwrap(),
+            "aaaa \n bbbb"
+        );
+        assert_eq!(
+            layout("  aa\n  bb", &opts, &make_font()).unwrap(),
+            "  aa\n  bb"
+        );
+
+        opts.leading_whitespace = LeadingWhitespace::TrimContinuation;
+        assert_eq!(
+            layout("aaaa  bbbb", &opts, &make_font()).unwrap(),
+            "aaaa \nbbbb"
+        );
+        // The trimmed whitespace doesn't take up room
+        assert_eq!(
+            layout("aaaa     bbbbb", &opts, &make_font()).unwrap(),
+            "aaaa \nbbbbb"
+        );
+        assert_eq!(
+            layout("  aa\n  bb", &opts, &make_font()).unwrap(),
+            "  aa\n  bb"
+        );
+
+        opts.leading_whitespace = LeadingWhitespace::TrimAll;
+        assert_eq!(
+            layout("aaaa  bbbb", &opts, &make_font()).unwrap(),
+            "aaaa \nbbbb"
+        );
+        assert_eq!(
+            layout("  aa\n  bb\n \n", &opts, &make_font()).unwrap(),
+            "  aa\nbb\n\n"
+        );
+    }
+
+    #[test]
+    fn break_after_delimiters() {
+        let mut opts = options(6, Overflow::Error);
+        opts.break_after = vec![',', ';'];
+
+        // Breaks go after the delimiter, never before it
+        assert_eq!(
+            layout("aa,bb,cc;dd,ee", &opts, &make_font()).unwrap(),
+            "aa,bb,\ncc;dd,\nee"
+        );
+        assert_eq!(layout("aaaaa,b", &opts, &make_font()).unwrap(), "aaaaa,\nb");
+        assert_eq!(
+            layout("aaaaaa,b", &opts, &make_font()).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "aaaaaa,".to_string()
+            }
+        );
+
+        // A space after a delimiter still stays at the end of the line
+        assert_eq!(
+            layout("aaaa, bb", &opts, &make_font()).unwrap(),
+            "aaaa, \nbb"
+        );
+
+        opts.break_after.clear();
+        assert!(layout("aa,bb,cc", &opts, &make_font()).is_err());
+    }
+
+    #[test]
+    fn no_break_after() {
+        let font = make_font();
+        let mut opts = options(6, Overflow::Error);
+        opts.no_break_after = vec!['-'];
+
+        assert_eq!(
+            layout("aa well-known", &options(8, Overflow::Error), &font).unwrap(),
+            "aa well-\nknown"
+        );
+        opts.max_width = 10;
+        assert_eq!(
+            layout("aa well-known", &opts, &font).unwrap(),
+            "aa \nwell-known"
+        );
+
+        opts.max_width = 4;
+        #[cfg(not(feature = "no-tables"))]
+        {
+            assert_eq!(
+                layout("ab/cd", &options(4, Overflow::Error), &font).unwrap(),
+                "ab/\ncd"
+            );
+            opts.no_break_after.push('/');
+            assert!(layout("ab/cd", &opts, &font).is_err());
+        }
+        // Even if they are in `break_after`
+        opts.break_after = vec!['-'];
+        assert!(layout("ab-cd", &opts, &font).is_err());
+
+        // Forced breaks keep them with the character after them too
+        opts.overflow = Overflow::Break;
+        assert_eq!(layout("abc-def", &opts, &font).unwrap(), "abc\n-def");
+
+        // Opening brackets already stay with what follows
+        for max_width in 1..30 {
+            let opts = options(max_width, Overflow::Error);
+            if let Ok(text) = layout("see (long content here)", &opts, &font) {
+                assert!(text.lines().all(|line| !line.ends_with('(')), "{:?}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn grapheme_clusters() {
+        let mut font = make_font();
+        font.insert('\u{301}', 1);
+        let text = "aaaae\u{301}b";
+        let clusters = [0, 1, 2, 3, 4, 7];
+
+        // Without clusters, the combining mark can be split from its base
+        let opts = options(5, Overflow::Break);
+        assert_eq!(layout(text, &opts, &font).unwrap(), "aaaae\n\u{301}b");
+        let result = layout_clusters(text, clusters, &opts, &font).unwrap();
+        assert_eq!(result.text, "aaaa\ne\u{301}b");
+        assert_eq!(result.emergency_breaks, [4]);
+
+        // Clusters count as one character
+        let mut opts = options(100, Overflow::Break);
+        opts.max_chars_per_line = Some(5);
+        let result = layout_clusters(text, clusters, &opts, &font).unwrap();
+        assert_eq!(result.text, "aaaae\u{301}\nb");
+
+        // Hard line breaks split clusters
+        let opts = options(2, Overflow::Break);
+        let result = layout_clusters("ab\ncd", [0], &opts, &font).unwrap();
+        assert_eq!(result.text, "ab\ncd");
+
+        // Even when the boundaries leave out the break, so the empty line after it isn't lost
+        for (text, boundaries) in [("a\n", &[0][..]), ("ab\n", &[0, 1]), ("ab\r\n", &[0])] {
+            let clustered = layout_clusters(text, boundaries.iter().copied(), &opts, &font);
+            let plain = layout_detailed(text, &opts, &font).unwrap();
+            assert_eq!(
+                clustered.unwrap().line_starts,
+                plain.line_starts,
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn marks_soft_breaks() {
+        let mut opts = options(5, Overflow::Break);
+        opts.soft_line_break = Some("\u{200B}\n".to_string());
+
+        let text = "aa bb cc\ndddddddd\r\n";
+        let output = layout(text, &opts, &make_font()).unwrap();
+        assert_eq!(output, "aa \u{200B}\nbb cc\nddddd\u{200B}\nddd\r\n");
+        assert_eq!(output.replace("\u{200B}\n", ""), text);
+
+        // Takes priority over normalized line endings
+        opts.normalize_line_endings = Some("\r\n".to_string());
+        assert_eq!(
+            layout(text, &opts, &make_font()).unwrap(),
+            "aa \u{200B}\nbb cc\r\nddddd\u{200B}\nddd\r\n"
+        );
+        opts.soft_line_break = None;
+        assert_eq!(
+            layout(text, &opts, &make_font()).unwrap(),
+            "aa \r\nbb cc\r\nddddd\r\nddd\r\n"
+        );
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        let mut opts = options(5, Overflow::Break);
+        let text = "aa\r\nbb\u{2028}cccccc\n\rd\n";
+        assert_eq!(
+            layout(text, &opts, &make_font()).unwrap(),
+            "aa\r\nbb\u{2028}ccccc\nc\n\rd\n"
+        );
+
+        opts.normalize_line_endings = Some("\r\n".to_string());
+        assert_eq!(
+            layout(text, &opts, &make_font()).unwrap(),
+            "aa\r\nbb\r\nccccc\r\nc\r\n\r\nd\r\n"
+        );
+
+        // Hard breaks cut off with an ellipsis are still written
+        let mut font = make_font();
+        font.insert('…', 1);
+        opts.overflow = Overflow::Ellipsis("…".to_string());
+        opts.normalize_line_endings = Some("\u{2028}".to_string());
+        assert_eq!(
+            layout("aaaaaaa\r\nb", &opts, &font).unwrap(),
+            "aaaa…\u{2028}b"
+        );
+    }
+
+    #[test]
+    fn reused_scratch() {
+        // One buffer, used again after texts of every length and after errors
+        let mut scratch = Scratch::new();
+        let mut rng = Rng::new(0);
+        for _ in 0..500 {
+            let text = rng.text();
+            let font = rng.font();
+            let options = LayoutOptions {
+                overflow: [Overflow::Error, Overflow::Break][rng.below(2)].clone(),
+                ansi_escapes: rng.below(4) == 0,
+                ..LayoutOptions::new(rng.below(12))
+            };
+            assert_eq!(
+                layout_reusing(&text, &options, &font, &mut scratch),
+                layout(&text, &options, &font),
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_options() {
+        let text = "aa bb cccccc";
+        let font = make_font();
+        let opts = LayoutOptions {
+            max_width: 5,
+            ..Default::default()
+        };
+        assert_eq!(opts, LayoutOptions::new(5));
+        assert_eq!(
+            layout(text, &opts, &font),
+            crate::apply_newlines(text, 5, &font)
+        );
+
+        // Without a width, nothing is wrapped
+        assert_eq!(
+            layout(text, &LayoutOptions::default(), &font).unwrap(),
+            text
+        );
+    }
+
+    #[test]
+    fn explicit_opportunities() {
+        use BreakOpportunity::{Allowed, Mandatory};
+
+        let font = make_font();
+        let opts = options(4, Overflow::Error);
+
+        // Codons of a DNA sequence, which has no break opportunities of its own
+        let dna = "ACGTTGCAA";
+        let codons = [(3, Allowed), (6, Allowed)];
+        let result = layout_opportunities(dna, &codons, &opts, &font).unwrap();
+        assert_eq!(result.text, "ACG\nTTG\nCAA");
+        assert_eq!(result.line_widths(), [3, 3, 3]);
+
+        // The text's own opportunities aren't used, and hard breaks need a mandatory one
+        let opts = options(6, Overflow::Error);
+        let text = "ab cd\nef";
+        let result = layout_opportunities(text, &[(1, Allowed)], &opts, &font).unwrap();
+        assert_eq!(result.line_starts, [0, 1]);
+        let breaks = [(1, Allowed), (6, Mandatory)];
+        let result = layout_opportunities(text, &breaks, &opts, &font).unwrap();
+        assert_eq!(result.text, "ab cd\nef");
+        assert_eq!(result.line_starts, [0, 6]);
+
+        assert_eq!(
+            layout_opportunities("abcdefg", &[], &opts, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "abcdefg".to_string()
+            }
+        );
+
+        // The text's own opportunities lay it out the usual way
+        for seed in 0..200 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let opts = options(rng.below(20), Overflow::Break);
+            let breaks: Vec<_> = line_break_opportunities(&text).collect();
+            assert_eq!(
+                layout_opportunities(&text, &breaks, &opts, &font),
+                layout_detailed(&text, &opts, &font)
+            );
+        }
+    }
+
+    #[test]
+    fn flows_around_other_content() {
+        let opts = options(10, Overflow::Break);
+        let text = "aa bb cc dd ee ff gg";
+
+        // The first two lines are beside something 6 wide
+        let flow = layout_flow(text, &opts, &make_font(), |line| {
+            Some(if line < 2 { 4 } else { 10 })
+        })
+        .unwrap();
+        assert_eq!(flow.layout.text, "aa \nbb \ncc dd ee \nff gg");
+        assert_eq!(flow.remainder, None);
+
+        // Only two lines fit, and wrapping the rest carries on from the third
+        let flow = layout_flow(text, &opts, &make_font(), |line| (line < 2).then_some(4)).unwrap();
+        assert_eq!(flow.layout.text, "aa \nbb ");
+        assert_eq!(flow.layout.line_starts, [0, 3]);
+        assert_eq!(&text[flow.remainder.unwrap()..], "cc dd ee ff gg");
+        // The break onto the next region isn't in this one
+        assert_eq!(flow.layout.breaks.len(), 1);
+        assert_eq!(flow.layout.breaks[0].offset, 3);
+
+        // Nor is an emergency break, if that was one
+        let flow = layout_flow("aaaaaa", &opts, &make_font(), |line| {
+            (line < 1).then_some(4)
+        })
+        .unwrap();
+        assert_eq!(flow.layout.text, "aaaa");
+        assert_eq!(flow.layout.breaks, []);
+        assert_eq!(flow.layout.emergency_breaks, []);
+
+        // Hard breaks stay part of the line
+        let flow = layout_flow("aa\nbb", &opts, &make_font(), |line| {
+            (line < 1).then_some(4)
+        })
+        .unwrap();
+        assert_eq!(flow.layout.text, "aa\n");
+        assert_eq!(flow.remainder, Some(3));
+
+        let flow = layout_flow(text, &opts, &make_font(), |_| None).unwrap();
+        assert_eq!(flow.layout, LayoutResult::default());
+        assert_eq!(flow.remainder, Some(0));
+
+        // The options are still checked when nothing fits
+        let invalid = LayoutOptions {
+            max_lines: Some(0),
+            ..opts.clone()
+        };
+        assert_eq!(
+            layout_flow(text, &invalid, &make_font(), |_| None),
+            Err(LineBreakErr::InvalidOptions(OptionError::NoLines))
+        );
+    }
+
+    #[test]
+    fn shaped_region() {
+        let opts = options(10, Overflow::Break);
+        let text = "aa bb cc dd ee ff gg";
+
+        // A triangle on the left, narrowing the lines as it goes in
+        let flow = layout_shaped(text, &opts, &make_font(), |line| {
+            let left = 6usize.saturating_sub(2 * line);
+            Some((left, 10 - left))
+        })
+        .unwrap();
+        assert_eq!(flow.layout.text, "aa \nbb cc \ndd ee \nff gg");
+        assert_eq!(flow.left_offsets, [6, 4, 2, 0]);
+        assert_eq!(flow.remainder, None);
+
+        // The shape runs out after two lines
+        let flow = layout_shaped(text, &opts, &make_font(), |line| {
+            (line < 2).then_some((line, 6))
+        })
+        .unwrap();
+        assert_eq!(flow.layout.text, "aa bb \ncc dd ");
+        assert_eq!(flow.left_offsets, [0, 1]);
+        assert_eq!(&text[flow.remainder.unwrap()..], "ee ff gg");
+
+        let flow = layout_flow(text, &opts, &make_font(), |line| (line < 2).then_some(4)).unwrap();
+        assert_eq!(flow.left_offsets, [0, 0]);
+    }
+
+    #[test]
+    fn flow_continues_from_remainder() {
+        for seed in 0..500 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let mut opts = options(rng.below(8) + 4, Overflow::Break);
+            opts.leading_whitespace = [
+                LeadingWhitespace::Keep,
+                LeadingWhitespace::TrimContinuation,
+                LeadingWhitespace::TrimAll,
+            ][rng.below(3)];
+            let Ok(whole) = layout_detailed(&text, &opts, &font) else {
+                continue;
+            };
+
+            // Fill pages of a random number of lines until the text runs out
+            let mut lines = Vec::new();
+            let mut offset = 0;
+            loop {
+                let page_lines = rng.below(3) + 1;
+                let flow = layout_flow(&text[offset..], &opts, &font, |line| {
+                    (line < page_lines).then_some(opts.max_width)
+                })
+                .unwrap();
+                lines.extend(flow.layout.lines().map(str::to_string));
+                match flow.remainder {
+                    Some(remainder) => offset += remainder,
+                    None => break,
+                }
+            }
+            assert_eq!(lines, whole.lines().collect::<Vec<_>>(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn underfill_slack() {
+        let mut opts = options(10, Overflow::Error);
+        opts.underfill_slack = Some(3);
+
+        assert_eq!(
+            layout("aaaa bbbb cccccc dd\nee", &opts, &make_font()).unwrap(),
+            "aaaa bbbb \ncccccc dd\nee"
+        );
+        assert_eq!(
+            layout("aaaa bbbb cc dddddddd e", &opts, &make_font()).unwrap_err(),
+            LineBreakErr::UnderfullLine { line: 1, width: 3 }
+        );
+
+        // Each line is checked against its own width
+        let flow = layout_flow("aa bb cc dd", &opts, &make_font(), |line| Some(3 + line));
+        assert_eq!(flow.unwrap().layout.text, "aa \nbb \ncc dd");
+        let widths = [3, 8, 10];
+        let flow = layout_flow("aa bb cccccc", &opts, &make_font(), |line| {
+            Some(widths[line])
+        });
+        assert_eq!(
+            flow.unwrap_err(),
+            LineBreakErr::UnderfullLine { line: 1, width: 3 }
+        );
+    }
+
+    #[test]
+    fn contextual_advances() {
+        /// Kerns "AV" and records what it was asked to measure.
+        struct Kerning(Vec<(char, Option<char>)>);
+        impl Advance for Kerning {
+            fn advance(&mut self, ch: char, prev: Option<char>) -> Option<usize> {
+                self.0.push((ch, prev));
+                match (prev, ch) {
+                    (Some('A'), 'V') => Some(1),
+                    (_, 'A' | 'V') => Some(2),
+                    (_, ' ') => Some(1),
+                    _ => None,
+                }
+            }
+        }
+
+        let opts = options(7, Overflow::Error);
+        let result = layout_advances("AV AV", &opts, &mut Kerning(Vec::new())).unwrap();
+        assert_eq!(result.text, "AV AV");
+
+        let opts = options(6, Overflow::Error);
+        let mut kerning = Kerning(Vec::new());
+        let result = layout_advances("AV VA", &opts, &mut kerning).unwrap();
+        assert_eq!(result.text, "AV \nVA");
+        // Pairs are never kerned across a line break
+        assert!(kerning.0.contains(&('V', Some(' '))));
+        assert!(kerning.0.contains(&('V', None)));
+        assert_eq!(
+            layout_advances("AVx", &opts, &mut kerning).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('x')
+        );
+
+        // Maps ignore the context
+        let result = layout_advances("aa bb", &opts, &mut make_font()).unwrap();
+        assert_eq!(result.text, "aa bb");
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn cluster_advances() {
+        /// Widens a base character with an acute accent on it.
+        struct Accents(Vec<(String, Option<char>)>);
+        impl Advance for Accents {
+            fn advance(&mut self, ch: char, _prev: Option<char>) -> Option<usize> {
+                match ch {
+                    '\u{301}' => Some(0),
+                    _ => Some(1),
+                }
+            }
+
+            fn advance_cluster(
+                &mut self,
+                cluster: &str,
+                prev: Option<char>,
+            ) -> Result<usize, char> {
+                self.0.push((cluster.to_string(), prev));
+                Ok(match cluster {
+                    "e\u{301}" => 3,
+                    _ => cluster.chars().count(),
+                })
+            }
+        }
+
+        let opts = options(4, Overflow::Break);
+        let mut accents = Accents(Vec::new());
+        let result = layout_advances("aaae\u{301}", &opts, &mut accents).unwrap();
+        assert_eq!(result.text, "aaa\ne\u{301}");
+        assert!(accents.0.contains(&("e\u{301}".to_string(), Some('a'))));
+        assert!(accents.0.contains(&("e\u{301}".to_string(), None)));
+
+        // The default adds up the characters
+        let mut font = make_font();
+        font.insert('\u{301}', 0);
+        let result = layout_advances("aaae\u{301}", &opts, &mut font).unwrap();
+        assert_eq!(result.text, "aaae\u{301}");
+        font.remove(&'\u{301}');
+        assert_eq!(
+            layout_advances("aaae\u{301}", &opts, &mut font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('\u{301}')
+        );
+    }
+
+    #[test]
+    fn hard_break_after_joiner() {
+        let mut font = make_font();
+        font.insert('\u{200D}', 0);
+        let opts = options(4, Overflow::Break);
+
+        // The line break ends the line, rather than joining the cluster before it
+        for text in ["a\u{200D}\n", "a\u{200D}\nb", "a\u{200D}\r\n\u{200D}b"] {
+            let advanced = layout_advances(text, &opts, &mut font.clone()).unwrap();
+            let plain = layout_detailed(text, &opts, &font).unwrap();
+            assert_eq!(advanced.line_starts, plain.line_starts, "{text:?}");
+            assert_eq!(advanced.text, plain.text, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn no_break_before_punctuation() {
+        let mut font = make_font();
+        font.insert('…', 1);
+        let mut opts = options(8, Overflow::Break);
+
+        // Without glue, the ellipsis and comma can be orphaned
+        assert_eq!(layout("aa wait …", &opts, &font).unwrap(), "aa wait \n…");
+        assert_eq!(layout("aaaaaaaa,", &opts, &font).unwrap(), "aaaaaaaa\n,");
+
+        opts.no_break_before = vec![',', '…', '.'];
+        assert_eq!(layout("aa wait …", &opts, &font).unwrap(), "aa \nwait …");
+        let result = layout_detailed("aaaaaaaa,", &opts, &font).unwrap();
+        assert_eq!(result.text, "aaaaaaa\na,");
+        assert_eq!(result.emergency_breaks, [7]);
+        assert_eq!(layout("aaaaaaa.,", &opts, &font).unwrap(), "aaaaaa\na.,");
+
+        // Unless there's nowhere else to break
+        assert_eq!(layout("a,,,,,,,,,", &opts, &font).unwrap(), "a,,,,,,,\n,,");
+    }
+
+    #[test]
+    fn first_line_indent() {
+        let font = make_font();
+        let mut opts = options(6, Overflow::Error);
+        opts.first_line_indent = Some("  ".to_string());
+
+        // Only the first line of each paragraph is indented, and the indent takes up room on it
+        let result = layout_detailed("aaa bbb ccc\ndd", &opts, &font).unwrap();
+        assert_eq!(result.text, "  aaa \nbbb \nccc\n  dd");
+        assert_eq!(result.line_starts, [0, 4, 8, 12]);
+        assert_eq!(
+            result.lines().collect::<Vec<_>>(),
+            ["  aaa ", "bbb ", "ccc", "  dd"]
+        );
+        assert_eq!(result.line_widths(), [6, 4, 3, 4]);
+
+        // Empty lines stay empty
+        assert_eq!(layout("a\n\nb\n", &opts, &font).unwrap(), "  a\n\n  b\n");
+
+        // An indent as wide as the line leaves no room for any text
+        opts.max_width = 2;
+        assert_eq!(
+            layout("a", &opts, &font).unwrap_err(),
+            LineBreakErr::NoAvailableWidth {
+                max_width: 2,
+                reserved: 2
+            }
+        );
+        opts.overflow = Overflow::Break;
+        assert_eq!(
+            layout("a", &opts, &font).unwrap_err(),
+            LineBreakErr::NoAvailableWidth {
+                max_width: 2,
+                reserved: 2
+            }
+        );
+        assert_eq!(layout("", &opts, &font).unwrap(), "");
+    }
+
+    #[test]
+    fn first_line_used_width() {
+        let font = make_font();
+        let mut opts = options(6, Overflow::Error);
+        opts.first_line_used_width = 3;
+
+        // Only the first line of the text has less room, and nothing is written for it
+        let result = layout_detailed("aa bb cc dd\nee", &opts, &font).unwrap();
+        assert_eq!(result.text, "aa \nbb cc \ndd\nee");
+        assert_eq!(result.line_widths(), [6, 6, 2, 2]);
+
+        // It adds to the indent
+        opts.first_line_indent = Some(" ".to_string());
+        assert_eq!(layout("a bb\ncc", &opts, &font).unwrap(), " a \nbb\n cc");
+        opts.first_line_indent = None;
+
+        opts.first_line_used_width = 6;
+        assert_eq!(
+            layout("a", &opts, &font).unwrap_err(),
+            LineBreakErr::NoAvailableWidth {
+                max_width: 6,
+                reserved: 6
+            }
+        );
+        assert_eq!(layout("\na", &opts, &font).unwrap(), "\na");
+    }
+
+    #[test]
+    fn no_break_spans() {
+        let font = make_font();
+        let text = "Hi {{user name}}, welcome";
+        let placeholder = text.find("{{").unwrap()..text.find("}}").unwrap() + 2;
+        let spans = |overflow| LayoutOptions {
+            overflow,
+            no_break_spans: vec![placeholder.clone()],
+            ..LayoutOptions::new(15)
+        };
+
+        assert_eq!(
+            layout(text, &options(15, Overflow::Error), &font).unwrap(),
+            "Hi {{user \nname}}, welcome"
+        );
+        // The span may still break at its ends
+        assert_eq!(
+            layout(text, &spans(Overflow::Error), &font).unwrap(),
+            "Hi \n{{user name}}, \nwelcome"
+        );
+
+        // A span too wide for a line is an unbreakable run
+        let narrow = LayoutOptions {
+            max_width: 8,
+            ..spans(Overflow::Error)
+        };
+        assert_eq!(
+            layout(text, &narrow, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 3,
+                run: "{{user name}},".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn forced_breaks() {
+        let font = make_font();
+        let token: String = (0..1000u32)
+            .map(|idx| char::from(b'a' + (idx % 26) as u8))
+            .collect();
+        let text = format!("key: {token}");
+        let forced = LayoutOptions {
+            force_break_every: Some(20),
+            ..LayoutOptions::new(30)
+        };
+
+        assert!(matches!(
+            layout(&text, &LayoutOptions::new(30), &font),
+            Err(LineBreakErr::NoLegalLinebreakOpportunity { .. })
+        ));
+        let result = layout_detailed(&text, &forced, &font).unwrap();
+        let lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines.len(), 50);
+        assert_eq!(lines[0], format!("key: {}", &token[..20]));
+        assert!(lines[1..].iter().all(|line| line.len() == 20));
+        assert_eq!(lines.concat(), text);
+        assert!(result.emergency_breaks.is_empty());
+
+        // The count starts again at every opportunity, and never splits off a combining mark
+        let mut font = make_font();
+        font.insert('\u{301}', 0);
+        let forced = LayoutOptions {
+            force_break_every: Some(3),
+            ..LayoutOptions::new(3)
+        };
+        assert_eq!(
+            layout("aaaa bbbbbbb", &forced, &font).unwrap(),
+            "aaa\na \nbbb\nbbb\nb"
+        );
+        // Without the tables, combining marks aren't told apart from letters
+        #[cfg(not(feature = "no-tables"))]
+        assert_eq!(
+            layout("aaa\u{301}a", &forced, &font).unwrap(),
+            "aaa\u{301}\na"
+        );
+    }
+
+    #[test]
+    fn collapsed_blank_lines() {
+        let font = make_font();
+        let collapsing = |leading_whitespace| LayoutOptions {
+            collapse_blank_lines: true,
+            leading_whitespace,
+            ..LayoutOptions::new(10)
+        };
+        let keep = collapsing(LeadingWhitespace::Keep);
+
+        assert_eq!(layout("a\n\n\n\nb", &keep, &font).unwrap(), "a\nb");
+        assert_eq!(layout("a\r\n\r\n\nb", &keep, &font).unwrap(), "a\r\nb");
+        assert_eq!(layout("\n\n\na\n\n", &keep, &font).unwrap(), "\na\n");
+        assert_eq!(count_lines("a\n\n\n\nb", &keep, &font), Ok(2));
+
+        // Whitespace is only blank once it's trimmed off
+        assert_eq!(layout("a\n  \n\nb", &keep, &font).unwrap(), "a\n  \nb");
+        let trim = collapsing(LeadingWhitespace::TrimAll);
+        assert_eq!(layout("a\n  \n\nb", &trim, &font).unwrap(), "a\nb");
+
+        // The dropped lines are part of the whitespace removed before the next one
+        let spans = layout_spans("a\n\n\nb", &keep, &font).unwrap();
+        assert_eq!(
+            spans,
+            [
+                LineSpan {
+                    text_range: 0..2,
+                    removed_whitespace: 0..0
+                },
+                LineSpan {
+                    text_range: 4..5,
+                    removed_whitespace: 2..4
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn line_limit() {
+        let font = make_font();
+        let limited = |max_width, max_lines| LayoutOptions {
+            overflow: Overflow::Break,
+            max_lines: Some(max_lines),
+            ..LayoutOptions::new(max_width)
+        };
+
+        assert_eq!(
+            layout("aa bb cc", &limited(3, 3), &font).unwrap(),
+            "aa \nbb \ncc"
+        );
+        assert_eq!(
+            layout("aa bb cc", &limited(3, 2), &font),
+            Err(LineBreakErr::TooManyLines { limit: 2 })
+        );
+        // The empty line after a trailing break counts
+        assert_eq!(
+            count_lines("a\n", &limited(3, 1), &font),
+            Err(LineBreakErr::TooManyLines { limit: 1 })
+        );
+
+        // A single character on every line would make the output twice the size of the input
+        let huge = "a".repeat(100_000);
+        assert_eq!(
+            layout_detailed(&huge, &limited(1, 1000), &font),
+            Err(LineBreakErr::TooManyLines { limit: 1000 })
+        );
+        assert_eq!(count_lines(&huge, &limited(1, 100_000), &font), Ok(100_000));
+    }
+
+    #[test]
+    fn invalid_options() {
+        let font = make_font();
+        let opts = LayoutOptions::new(10);
+        assert_eq!(validate(&opts), Ok(()));
+
+        let rejected = |opts: LayoutOptions| {
+            let err = validate(&opts).unwrap_err();
+            // Laying out fails with the same error, even for empty text
+            assert_eq!(
+                layout("", &opts, &font),
+                Err(LineBreakErr::InvalidOptions(err.clone()))
+            );
+            err
+        };
+        assert_eq!(
+            rejected(LayoutOptions {
+                max_lines: Some(0),
+                ..opts.clone()
+            }),
+            OptionError::NoLines
+        );
+        assert_eq!(
+            rejected(LayoutOptions {
+                max_chars_per_line: Some(0),
+                ..opts.clone()
+            }),
+            OptionError::NoCharsPerLine
+        );
+        assert_eq!(
+            rejected(LayoutOptions {
+                force_break_every: Some(0),
+                ..opts.clone()
+            }),
+            OptionError::ForcedBreakEveryChar
+        );
+        assert_eq!(
+            rejected(LayoutOptions {
+                soft_line_break: Some(String::new()),
+                ..opts.clone()
+            }),
+            OptionError::EmptyLineBreak {
+                option: "soft_line_break"
+            }
+        );
+        #[allow(clippy::reversed_empty_ranges)]
+        let backwards = 5..2;
+        assert_eq!(
+            rejected(LayoutOptions {
+                no_break_spans: vec![0..1, backwards],
+                ..opts.clone()
+            }),
+            OptionError::BackwardsSpan { start: 5, end: 2 }
+        );
+
+        let mut hyphenated = LayoutOptions {
+            hyphen_min_left: 2,
+            ..opts.clone()
+        };
+        assert_eq!(
+            rejected(hyphenated.clone()),
+            OptionError::HyphenMinWithoutHyphen
+        );
+        hyphenated.soft_hyphen = Some("-".to_string());
+        assert_eq!(validate(&hyphenated), Ok(()));
+    }
+
+    #[test]
+    fn width_exclusive() {
+        let font = make_font();
+        let mut opts = options(5, Overflow::Error);
+        let text = "aaa bbbbb";
+
+        // A run exactly as wide as the line fits unless the width is exclusive
+        assert_eq!(layout(text, &opts, &font).unwrap(), "aaa \nbbbbb");
+        opts.width_inclusive = false;
+        assert_eq!(
+            layout(text, &opts, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 4,
+                run: "bbbbb".to_string()
+            }
+        );
+        assert_eq!(layout("aaa bbbb", &opts, &font).unwrap(), "aaa \nbbbb");
+
+        // Nothing can reach the boundary, including an ellipsis
+        opts.overflow = Overflow::Ellipsis("..".to_string());
+        assert_eq!(layout("aaaaaa", &opts, &font).unwrap(), "aa..");
+        opts.overflow = Overflow::Break;
+        assert_eq!(layout("aaaaaa", &opts, &font).unwrap(), "aaaa\naa");
+
+        // But an indent can leave less room than one character
+        opts.first_line_indent = Some("    ".to_string());
+        assert_eq!(layout("aa", &opts, &font).unwrap(), "    a\na");
+        opts.first_line_indent = Some("     ".to_string());
+        assert_eq!(
+            layout("aaa", &opts, &font).unwrap_err(),
+            LineBreakErr::NoAvailableWidth {
+                max_width: 5,
+                reserved: 5
+            }
+        );
+    }
+
+    #[test]
+    fn spans_round_trip() {
+        let font = make_font();
+        let mut opts = options(4, Overflow::Break);
+        opts.leading_whitespace = LeadingWhitespace::TrimAll;
+
+        let spans = layout_spans("aa bbbbb\n  c", &opts, &font).unwrap();
+        assert_eq!(
+            spans,
+            [
+                LineSpan {
+                    text_range: 0..3,
+                    removed_whitespace: 0..0
+                },
+                LineSpan {
+                    text_range: 3..7,
+                    removed_whitespace: 3..3
+                },
+                LineSpan {
+                    text_range: 7..9,
+                    removed_whitespace: 7..7
+                },
+                LineSpan {
+                    text_range: 11..12,
+                    removed_whitespace: 9..11
+                },
+            ]
+        );
+
+        for seed in 0..1000 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let mut font = rng.font();
+            font.insert('…', 1);
+            let mut opts = options(1 + rng.below(20), Overflow::Break);
+            opts.leading_whitespace = [
+                LeadingWhitespace::Keep,
+                LeadingWhitespace::TrimContinuation,
+                LeadingWhitespace::TrimAll,
+            ][rng.below(3)];
+            if rng.below(2) == 0 {
+                opts.overflow = Overflow::Ellipsis("…".to_string());
+            }
+
+            let spans = layout_spans(&text, &opts, &font).unwrap();
+            let reassembled: String = spans
+                .iter()
+                .map(|span| {
+                    format!(
+                        "{}{}",
+                        &text[span.removed_whitespace.clone()],
+                        &text[span.text_range.clone()]
+                    )
+                })
+                .collect();
+            assert_eq!(reassembled, text);
+            assert_eq!(
+                spans
+                    .iter()
+                    .map(|span| span.text_range.start)
+                    .collect::<Vec<_>>(),
+                layout_detailed(&text, &opts, &font).unwrap().line_starts
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn fit_with_soft_hyphens() {
+        let mut font = make_font();
+        font.insert('\u{AD}', 0);
+        font.insert('-', 3);
+        let mut opts = options(6, Overflow::Error);
+        let text = "aaa\u{AD}b cc";
+
+        // Without a hyphen, a soft hyphen is just another break opportunity
+        assert_eq!(layout(text, &opts, &font).unwrap(), "aaa\u{AD}b \ncc");
+
+        // The hyphen makes breaking at the soft hyphen fill the line more than the later space
+        opts.soft_hyphen = Some("-".to_string());
+        let result = layout_detailed(text, &opts, &font).unwrap();
+        assert_eq!(result.text, "aaa\u{AD}b \ncc");
+        assert_eq!(result.line_widths(), [5, 2]);
+
+        opts.fit = Fit::Closest;
+        let result = layout_detailed(text, &opts, &font).unwrap();
+        assert_eq!(result.text, "aaa\u{AD}-\nb cc");
+        assert_eq!(result.line_widths(), [6, 4]);
+
+        // Unless the hyphen doesn't fit
+        opts.max_width = 5;
+        assert_eq!(layout(text, &opts, &font).unwrap(), "aaa\u{AD}b \ncc");
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn hyphen_min() {
+        let mut font = make_font();
+        font.insert('\u{AD}', 0);
+        font.insert('x', 2);
+        let mut opts = options(4, Overflow::Error);
+        opts.soft_hyphen = Some("-".to_string());
+
+        let text = "abc\u{AD}x y";
+        assert_eq!(layout(text, &opts, &font).unwrap(), "abc\u{AD}-\nx y");
+
+        // A break one character from the end of the word is too close
+        opts.hyphen_min_right = 2;
+        assert!(layout(text, &opts, &font).is_err());
+
+        // Other soft hyphens in the word don't count
+        opts.hyphen_min_right = 0;
+        opts.hyphen_min_left = 2;
+        let text = "a\u{AD}b\u{AD}cdef";
+        assert_eq!(layout(text, &opts, &font).unwrap(), "a\u{AD}b\u{AD}-\ncdef");
+        opts.hyphen_min_left = 3;
+        assert!(layout(text, &opts, &font).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn prefer_spaces() {
+        let font = make_font();
+        let mut opts = options(20, Overflow::Error);
+        let text = "see path/to/very/long thing";
+
+        assert_eq!(
+            layout(text, &opts, &font).unwrap(),
+            "see path/to/very/\nlong thing"
+        );
+
+        opts.prefer_spaces = true;
+        assert_eq!(
+            layout(text, &opts, &font).unwrap(),
+            "see \npath/to/very/long \nthing"
+        );
+
+        // Other opportunities are still used when there's no whitespace to break at
+        opts.max_width = 10;
+        assert_eq!(
+            layout("path/to/very/long", &opts, &font).unwrap(),
+            "path/to/\nvery/long"
+        );
+    }
+
+    #[test]
+    fn ansi_escapes_have_no_width() {
+        let font = make_font();
+        let mut opts = options(7, Overflow::Error);
+        opts.ansi_escapes = true;
+
+        // The color code stays with the word after it, and the reset with the space after that
+        let text = "aaa \x1b[31mbbb\x1b[0m ccc";
+        let result = layout_detailed(text, &opts, &font).unwrap();
+        assert_eq!(result.text, "aaa \n\x1b[31mbbb\x1b[0m ccc");
+        assert_eq!(result.line_widths(), [4, 7]);
+
+        // Emergency breaks and hard breaks don't split them either
+        opts.overflow = Overflow::Break;
+        opts.max_width = 3;
+        assert_eq!(
+            layout("\x1b[1;31maaaa\x1b[0mbb", &opts, &font).unwrap(),
+            "\x1b[1;31maaa\na\x1b[0mbb"
+        );
+        assert_eq!(
+            layout_detailed("aa\x1b[0m\n\x1b[32mbb\n\x1b[0m", &opts, &font)
+                .unwrap()
+                .line_starts,
+            [0, 7, 15]
+        );
+
+        // An incomplete escape is just text
+        assert_eq!(layout("\x1b[31", &opts, &font).unwrap(), "\x1b[3\n1");
+
+        // Without the option, the escape is measured like anything else
+        opts.ansi_escapes = false;
+        opts.max_width = 7;
+        assert_eq!(
+            layout(text, &opts, &font).unwrap(),
+            "aaa \n\x1b[31mbb\nb\x1b[0m \nccc"
+        );
+    }
+
+    #[test]
+    fn overflow_per_line() {
+        let mut font = make_font();
+        font.insert('…', 1);
+        let opts = options(3, Overflow::Error);
+
+        // Break the first two lines, and cut the third short
+        let mut indices = Vec::new();
+        let result = layout_overflow("aaaaaaaaaa", &opts, &font, |index| {
+            indices.push(index);
+            if index < 2 {
+                Overflow::Break
+            } else {
+                Overflow::Ellipsis("…".to_string())
+            }
+        })
+        .unwrap();
+        assert_eq!(result.text, "aaa\naaa\naa…");
+        assert_eq!(result.emergency_breaks, [3, 6]);
+        assert_eq!(indices, [0, 1, 2]);
+
+        // Lines that need no overflow handling don't care what it is
+        let opts = options(4, Overflow::Error);
+        assert_eq!(
+            layout_overflow("aaa bbbbb", &opts, &font, |index| match index {
+                0 => Overflow::Error,
+                _ => Overflow::Break,
+            })
+            .unwrap()
+            .text,
+            "aaa \nbbbb\nb"
+        );
+        assert!(layout_overflow("aaa bbbbb", &opts, &font, |_| Overflow::Error).is_err());
+    }
+
+    #[test]
+    fn block_heights() {
+        let font = make_font();
+        let heights = HashMap::from([('a', 8), ('b', 10), ('g', 12), (' ', 0)]);
+        let opts = options(4, Overflow::Error);
+
+        let block = layout_block("aa bg\n\nxa", &opts, &font, Some(&heights), 9).unwrap();
+        assert_eq!(block.layout.text, "aa \nbg\n\nxa");
+        assert_eq!(block.line_heights, [8, 12, 9, 9]);
+        assert_eq!(block.line_tops, [0, 8, 20, 29]);
+        assert_eq!(block.height, 38);
+
+        // Without heights, every line is the same height
+        let block = layout_block("aa bg", &opts, &font, None, 9).unwrap();
+        assert_eq!(block.line_heights, [9, 9]);
+        assert_eq!(block.line_tops, [0, 9]);
+        assert_eq!(block.height, 18);
+
+        assert_eq!(layout_block("", &opts, &font, None, 9).unwrap().height, 9);
+    }
+
+    #[test]
+    fn control_characters() {
+        let mut font = make_font();
+        font.insert('\0', 0);
+        let mut opts = options(3, Overflow::Error);
+
+        // NUL is measured like anything else, and nothing after it is lost
+        let result = layout_detailed("a\0b c", &opts, &font).unwrap();
+        assert_eq!(result.text, "a\0b \nc");
+        font.remove(&'\0');
+        assert_eq!(
+            layout_detailed("a\0b", &opts, &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('\0')
+        );
+
+        opts.reject_controls = true;
+        assert_eq!(
+            layout_detailed("a\0b", &opts, &font).unwrap_err(),
+            LineBreakErr::UnexpectedControl {
+                ch: '\0',
+                offset: 1
+            }
+        );
+        assert_eq!(
+            layout_detailed("ab c\n\u{8}", &opts, &font).unwrap_err(),
+            LineBreakErr::UnexpectedControl {
+                ch: '\u{8}',
+                offset: 5
+            }
+        );
+        // Hard line breaks and escape sequences aren't rejected
+        opts.ansi_escapes = true;
+        let result = layout_detailed("ab\r\n\x1b[1mc\u{85}\x0C", &opts, &font).unwrap();
+        assert_eq!(result.text, "ab\r\n\x1b[1mc\u{85}\x0C");
+        assert_eq!(
+            layout_detailed("\t", &opts, &font).unwrap_err(),
+            LineBreakErr::UnexpectedControl {
+                ch: '\t',
+                offset: 0
+            }
+        );
+    }
+
+    #[test]
+    fn zero_width_characters() {
+        let font = make_font();
+        let mut opts = options(3, Overflow::Error);
+        opts.zero_width = vec!['\u{E000}'];
+
+        let result = layout_detailed("ab\u{E000} cd\u{E000}", &opts, &font).unwrap();
+        assert_eq!(result.text, "ab\u{E000} \ncd\u{E000}");
+        assert_eq!(result.line_widths(), [3, 2]);
+
+        // Lines don't break on either side of them
+        opts.max_width = 4;
+        let result = layout("a \u{E000}b c", &opts, &font).unwrap();
+        assert_eq!(result, "a \u{E000}b \nc");
+        opts.max_width = 2;
+        assert!(matches!(
+            layout("a \u{E000}b c", &opts, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity { offset: 0, .. }
+        ));
+        assert_eq!(
+            layout("aa\u{E000}\nb", &opts, &font).unwrap(),
+            "aa\u{E000}\nb"
+        );
+    }
+
+    #[test]
+    fn line_budgets() {
+        let font = make_font();
+        let mut opts = options(6, Overflow::Error);
+        opts.first_line_indent = Some("  ".to_string());
+        opts.first_line_used_width = 1;
+
+        let budget = |max_width, reserved, width| LineBudget {
+            max_width,
+            reserved,
+            width,
+        };
+        let result = layout_detailed("aa bbb cc\ndd\n", &opts, &font).unwrap();
+        assert_eq!(
+            result.line_budgets(),
+            [
+                budget(6, 3, 6),
+                budget(6, 0, 6),
+                budget(6, 2, 4),
+                budget(6, 0, 0)
+            ]
+        );
+
+        // Lines with their own widths report them
+        opts.first_line_indent = None;
+        opts.first_line_used_width = 0;
+        let flow = layout_flow("aa bb cc dd", &opts, &font, |line| Some(3 + line)).unwrap();
+        assert_eq!(
+            flow.layout.line_budgets(),
+            [budget(3, 0, 3), budget(4, 0, 3), budget(5, 0, 5)]
+        );
+    }
+
+    #[test]
+    fn continuation_marker() {
+        let font = make_font();
+        let mut opts = options(6, Overflow::Error);
+        opts.continuation_marker = Some("\\".to_string());
+
+        // Without the marker, "aa bb " would fit on the first line
+        assert_eq!(
+            layout("aa bb cc", &options(6, Overflow::Error), &font).unwrap(),
+            "aa bb \ncc"
+        );
+        let result = layout_detailed("aa bb cc\ndd", &opts, &font).unwrap();
+        assert_eq!(result.text, "aa \\\nbb cc\ndd");
+        assert_eq!(result.line_widths(), [4, 5, 2]);
+        assert_eq!(result.trimmed_line_widths().collect::<Vec<_>>(), [4, 5, 2]);
+
+        // Forced breaks make room for it too
+        opts.max_width = 4;
+        opts.overflow = Overflow::Break;
+        assert_eq!(
+            layout("abcdefgh", &opts, &font).unwrap(),
+            "abc\\\ndef\\\ngh"
+        );
+
+        // It comes after a hyphen, and both have to fit
+        #[cfg(not(feature = "no-tables"))]
+        {
+            opts.overflow = Overflow::Error;
+            opts.soft_hyphen = Some("-".to_string());
+            opts.max_width = 5;
+            assert_eq!(
+                layout("aa\u{AD}bbbb", &opts, &font).unwrap(),
+                "aa\u{AD}-\\\nbbbb"
+            );
+            opts.max_width = 4;
+            assert!(layout("aa\u{AD}bbbb", &opts, &font).is_err());
+        }
+    }
+
+    #[test]
+    fn always_finishes() {
+        let texts = [
+            "",
+            "\u{200B}\u{200B}\u{200B}",
+            "\n\n\r\n",
+            "a\u{AD}\u{AD}\u{AD}b",
+            "   a   ",
+            "\x1b[0m\x1b[1m\x1b[0m",
+            "中中中中",
+        ];
+        let overflows = [
+            Overflow::Error,
+            Overflow::Break,
+            Overflow::Ellipsis(String::new()),
+            Overflow::Ellipsis("..".to_string()),
+        ];
+
+        for text in texts {
+            for zero_width in [false, true] {
+                let font = monospace_font(usize::from(!zero_width), text.chars().chain(['.', '-']));
+                for max_width in 0..3 {
+                    for overflow in &overflows {
+                        for variant in 0..4 {
+                            let mut opts = options(max_width, overflow.clone());
+                            match variant {
+                                0 => {}
+                                1 => opts.continuation_marker = Some("-".to_string()),
+                                2 => {
+                                    opts.soft_hyphen = Some("-".to_string());
+                                    opts.leading_whitespace = LeadingWhitespace::TrimAll;
+                                }
+                                _ => {
+                                    opts.ansi_escapes = true;
+                                    opts.zero_width = vec!['\u{200B}'];
+                                }
+                            }
+
+                            // Every line but the last takes at least one character
+                            if let Ok(result) = layout_detailed(text, &opts, &font) {
+                                assert!(result.line_starts.len() <= text.chars().count() + 1);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_width_spaces() {
+        let mut font = make_font();
+        font.insert(' ', 0);
+        let mut opts = options(4, Overflow::Error);
+
+        // Spaces still give places to break, and add nothing to the line
+        let result = layout_detailed("aa bb cc", &opts, &font).unwrap();
+        assert_eq!(result.text, "aa bb \ncc");
+        assert_eq!(result.line_widths(), [4, 2]);
+        assert_eq!(result.trimmed_line_widths().collect::<Vec<_>>(), [4, 2]);
+
+        // However many there are
+        let text = format!("a{}b", " ".repeat(10_000));
+        opts.max_width = 1;
+        assert_eq!(count_lines(&text, &opts, &font).unwrap(), 2);
+        opts.leading_whitespace = LeadingWhitespace::TrimAll;
+        assert_eq!(
+            layout(&format!("a\n{}b", " ".repeat(100)), &opts, &font).unwrap(),
+            "a\nb"
+        );
+
+        // Text that is all zero width is never wrapped
+        let font = monospace_font(0, ['a', ' ']);
+        assert_eq!(count_lines(&"a a".repeat(1000), &opts, &font).unwrap(), 1);
+    }
+}
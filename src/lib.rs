@@ -1,106 +1,535 @@
 //! This is a basic newline layout library. It is designed for very limited uses, and users are encouraged to find a better layouting solution.
+//!
+//! Fonts map each character to its width. Hard line break characters (`\n`, `\r`, vertical tab,
+//! form feed, NEL, LINE SEPARATOR and PARAGRAPH SEPARATOR) are never measured: a font doesn't need
+//! widths for them, and any widths it has for them are ignored. Other characters may be zero
+//! wide, even spaces: the text can still break after them, they just take up no room, so any
+//! number of them fit on a line.
+//!
+//! Lines are counted the way a text editor shows them: empty text is one empty line, and text
+//! ending in a hard line break has an empty line after it. So `""` and `"a"` are one line,
+//! `"a\n"` is two and `"a\n\n"` is three. [`count_lines`], [`LayoutResult::line_starts`],
+//! [`LayoutResult::lines`], [`layout_spans`], [`wrap_iter`] and [`wrap_tagged`] all count them
+//! this way, and the text from [`layout`] has one line break fewer than that. Only [`hard_lines`],
+//! which splits rather than lays out, follows [`str::lines`] instead.
+//!
+//! The `no-tables` feature leaves out the Unicode line breaking tables to make the binary much
+//! smaller, for targets that only ever lay out ASCII. Breaks are then only allowed after spaces,
+//! tabs and hyphens, and every other character is treated as [`BreakClass::Alphabetic`], so text
+//! in scripts like Chinese or Thai, which break without spaces, can no longer be wrapped. The API
+//! is the same either way.
+//!
+//! The `sample-fonts` feature adds ready-made font maps to try the crate out with: a terminal font
+//! measured in cells, and a small proportional pixel font with uneven widths.
+//!
+//! The `svg` feature adds `to_svg_tspans`, which writes the lines as SVG `<tspan>` elements.
+
+// The table constants in shared.rs are only used with the tables
+#![cfg_attr(feature = "no-tables", allow(dead_code))]
 
 use std::collections::HashMap;
+use std::ops::Range;
 
 use core::iter::once;
-use core::mem;
 
 include!("shared.rs");
+#[cfg(not(feature = "no-tables"))]
 include!(concat!(env!("OUT_DIR"), "/tables.rs"));
 
+#[cfg(feature = "no-tables")]
+mod ascii;
+mod engine;
+mod font;
+mod grid;
+mod incremental;
+mod justify;
+mod layout;
+mod measure;
+#[cfg(feature = "svg")]
+mod svg;
+mod writer;
+
+#[cfg(feature = "no-tables")]
+use ascii::{break_property, scan_step, ScanState, SCAN_START};
+pub use engine::LayoutEngine;
+pub use font::{ascii_monospace, monospace_font, validate_coverage, Advance, CachedFont};
+#[cfg(feature = "sample-fonts")]
+pub use font::{sample_pixel_font, terminal_font};
+pub use grid::{to_grid, Cell};
+pub use incremental::{rewrap_range, Rewrap};
+pub use justify::{align, justify, Align, Direction, Gap, LastLine};
+pub use layout::{
+    count_lines, layout, layout_advances, layout_block, layout_cached, layout_clusters,
+    layout_detailed, layout_flow, layout_opportunities, layout_overflow, layout_reusing,
+    layout_runs, layout_shaped, layout_spans, layout_with_overrides, validate, Block, ChosenBreak,
+    Fit, Flow, LayoutOptions, LayoutResult, LeadingWhitespace, LineBudget, LineSpan, OptionError,
+    Overflow, Scratch,
+};
+use layout::{sum_chars, LineEnd, Lines};
+pub use measure::{
+    align_decimals, analyze, analyze_iter, content_width, fits, measure_range, measure_width,
+    measure_width_iter, min_unbreakable_width, offset_at, width_prefix_sums, BreakInfo, TextWidth,
+};
+#[cfg(feature = "svg")]
+pub use svg::to_svg_tspans;
+pub use writer::WrappingWriter;
+
 /// Returns newlines where this text needs it.
 pub fn apply_newlines(
     input: &str,
     max_width: usize,
     font: &HashMap<char, usize>,
 ) -> Result<String, LineBreakErr> {
-    // Set up our output string and retrieve our linebreak information
-    let mut output = String::new();
-    let mut breakers = linebreaks(input);
+    layout(input, &LayoutOptions::new(max_width), font)
+}
+
+/// Like [`apply_newlines`], but keeping its working memory in `scratch` to use again on the next
+/// call, as [`layout_reusing`] does. Whatever `scratch` held before is overwritten.
+pub fn apply_newlines_reusing(
+    input: &str,
+    max_width: usize,
+    font: &HashMap<char, usize>,
+    scratch: &mut Scratch,
+) -> Result<String, LineBreakErr> {
+    layout_reusing(input, &LayoutOptions::new(max_width), font, scratch)
+}
+
+/// Wraps this text the same way as [`apply_newlines`], yielding each line as it is wrapped.
+///
+/// Lines don't include the line break after them. A trailing hard line break is followed by an
+/// empty last line, as in [`LayoutResult::line_starts`], so joining the lines with `\n` gives the
+/// same text as [`apply_newlines`] when the input only has `\n` line breaks. After an error,
+/// nothing more is yielded.
+pub fn wrap_iter<'a>(
+    input: &'a str,
+    max_width: usize,
+    font: &'a HashMap<char, usize>,
+) -> impl Iterator<Item = Result<String, LineBreakErr>> + 'a {
+    let width_of = sum_chars(|_, c| font.get(&c).copied());
+    Lines::new_owned(input, LayoutOptions::new(max_width), width_of).map(move |line| {
+        let line = line?;
+        let text = &input[line.start..line.kept];
+        Ok(text.trim_end_matches(is_hard_break).to_string())
+    })
+}
 
-    let mut chars: Vec<(char, Option<BreakOpportunity>)> = input
-        .char_indices()
-        .map(|(_, c)| (c, breakers.next().expect("linebreak issue in `inner`").1))
+/// Wraps a single paragraph the same way as [`apply_newlines`], returning its lines.
+///
+/// This is for callers that have already split their text into paragraphs, and want the lines of
+/// each without any line break characters to strip off them. The paragraph mustn't contain hard
+/// line breaks, which debug builds check for. In release builds, they still end lines the same
+/// way as in [`wrap_iter`], so a trailing one is followed by an empty last line. Each line keeps
+/// the whitespace it was wrapped after.
+pub fn wrap_paragraph(
+    paragraph: &str,
+    max_width: usize,
+    font: &HashMap<char, usize>,
+) -> Result<Vec<String>, LineBreakErr> {
+    debug_assert!(
+        !paragraph.contains(is_hard_break),
+        "wrap_paragraph was given a hard line break: {paragraph:?}"
+    );
+    wrap_iter(paragraph, max_width, font).collect()
+}
+
+/// How a line returned by [`wrap_tagged`] ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakKind {
+    /// The line was wrapped onto the next one, so it can be reflowed.
+    Soft,
+    /// The line ends with a hard line break from the text.
+    Hard,
+    /// The line is the last one.
+    EndOfText,
+}
+
+/// Wraps this text the same way as [`apply_newlines`], returning each line with how it ends.
+///
+/// Lines are slices of `string`, without the hard line break after them, as in [`wrap_iter`].
+/// Text ending in a hard line break ends with an empty line, tagged [`BreakKind::EndOfText`].
+pub fn wrap_tagged<'a>(
+    string: &'a str,
+    max_width: usize,
+    font: &HashMap<char, usize>,
+) -> Result<Vec<(&'a str, BreakKind)>, LineBreakErr> {
+    let options = LayoutOptions::new(max_width);
+    Lines::new(string, &options, sum_chars(|_, c| font.get(&c).copied()))
+        .map(|line| {
+            let line = line?;
+            let kind = match line.end {
+                LineEnd::Soft => BreakKind::Soft,
+                LineEnd::Hard => BreakKind::Hard,
+                LineEnd::EndOfText => BreakKind::EndOfText,
+            };
+            let text = &string[line.start..line.kept];
+            Ok((text.trim_end_matches(is_hard_break), kind))
+        })
+        .collect()
+}
+
+/// Joins the lines of a paragraph back together, and wraps them again to a new width.
+///
+/// Each line is the text of one wrapped line, without the newline added for wrapping it, and
+/// the returned lines are in the same form, so they can be passed back in when the width changes
+/// again. Wrapping keeps the whitespace at each break at the end of the line before it, so no
+/// whitespace has to be put back: the lines are joined as they are, and a line ending in a hard
+/// line break still ends in one. Lines wrapped with [`LeadingWhitespace`] trimming have lost that
+/// whitespace, so they can't be rejoined exactly.
+pub fn unwrap_and_rewrap(
+    lines: &[&str],
+    max_width: usize,
+    font: &HashMap<char, usize>,
+) -> Result<Vec<String>, LineBreakErr> {
+    let text = lines.concat();
+    let line_starts = layout_detailed(&text, &LayoutOptions::new(max_width), font)?.line_starts;
+
+    Ok(line_starts
+        .iter()
+        .zip(line_starts.iter().skip(1).chain([&text.len()]))
+        .map(|(&start, &end)| text[start..end].to_string())
+        .collect())
+}
+
+/// Returns the largest width that [`apply_newlines`] breaks `string` in the same places at as it
+/// does at `current_width`.
+///
+/// Widening only changes the layout once the next run after some wrapped line fits on the end
+/// of it, so this is one less than the narrowest such line would need. Any width from
+/// `current_width` up to the result gives the same line breaks, so the layout doesn't need to be
+/// redone. Text with no wrapped lines is stable at any width, and returns `usize::MAX`.
+pub fn stable_up_to(
+    string: &str,
+    current_width: usize,
+    font: &HashMap<char, usize>,
+) -> Result<usize, LineBreakErr> {
+    let result = layout_detailed(string, &LayoutOptions::new(current_width), font)?;
+    let opportunities: Vec<usize> = linebreaks(string)
+        .filter(|(_, break_op)| break_op.is_some())
+        .map(|(offset, _)| offset)
         .collect();
 
-    // Iterate over our input until
-    // we have successfully processed the whole thing.
-    loop {
-        let mut current_width = 0;
-        let mut break_point: Option<usize> = None;
-        let mut applied_line_break = false;
-
-        for (cursor, (c, break_op)) in chars.iter().enumerate() {
-            // Break on null terminator -- we probably shouldn't find any of these...
-            if *c == '\0' {
-                break;
-            }
+    let mut stable = usize::MAX;
+    for (&start, chosen) in result.line_starts.iter().zip(&result.breaks) {
+        if chosen.mandatory {
+            continue;
+        }
 
-            // Reset on newlines
-            if *c == '\n' {
-                current_width = 0;
-                continue;
-            }
+        // Every soft break is followed by at least the opportunity at the end of the text
+        let next = opportunities[opportunities.partition_point(|&idx| idx <= chosen.offset)];
+        let width = measure_width_iter(string[start..next].chars(), font)?;
+        stable = stable.min(width - 1);
+    }
 
-            // Add the width of this character
-            current_width += font.get(c).ok_or(LineBreakErr::MissingCharacterWidth(*c))?;
+    Ok(stable)
+}
 
-            // We weren't over the limit, so we can continue -- but if this is a safe
-            // break point, let's remember that
-            if break_op.is_some() && cursor != 0 {
-                break_point = Some(cursor);
-            }
+/// Returns the widths within `bounds` at which [`apply_newlines`] wraps `string` to exactly
+/// `lines` lines, or `None` if no width in `bounds` does.
+///
+/// The widths are returned as a range, from the narrowest such width up to, but not including,
+/// the first width that gives fewer lines. Wider lines never need more lines, so this only takes
+/// two binary searches. Widths too narrow for some run of the text to fit are skipped over.
+pub fn width_range_for_lines(
+    string: &str,
+    lines: usize,
+    font: &HashMap<char, usize>,
+    bounds: Range<usize>,
+) -> Result<Option<Range<usize>>, LineBreakErr> {
+    // Every text has at least one line
+    let Some(fewer) = lines.checked_sub(1) else {
+        return Ok(None);
+    };
+    let at_most = |max_lines: usize, width: usize| match count_lines(
+        string,
+        &LayoutOptions::new(width),
+        font,
+    ) {
+        Ok(count) => Ok(count <= max_lines),
+        Err(LineBreakErr::NoLegalLinebreakOpportunity { .. }) => Ok(false),
+        Err(err) => Err(err),
+    };
 
-            // Are we over the max width now? If so, create a linebreak at our last
-            // safe break point
-            if current_width > max_width {
-                if let Some(break_point) = break_point {
-                    use std::fmt::Write;
-
-                    // Create the split
-                    let (prefix, postfix) = chars.split_at(break_point);
-                    let prefix: String = prefix.iter().map(|&(c, _)| c).collect();
-                    writeln!(output, "{}", prefix).unwrap();
-
-                    // We will now modify chars so that if we need to run again, we will only be
-                    // iterating on the unprocessed characters.
-                    chars = postfix.to_vec();
-                    applied_line_break = true;
-                    break;
-                } else {
-                    return Err(LineBreakErr::NoLegalLinebreakOpportunity);
-                }
+    // Finds the first width in `range` that wraps to at most `max_lines` lines
+    let narrowest = |max_lines: usize, range: Range<usize>| {
+        let (mut low, mut high) = (range.start, range.end);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if at_most(max_lines, mid)? {
+                high = mid;
+            } else {
+                low = mid + 1;
             }
-
         }
+        Ok(low)
+    };
 
-        // Once we're here, we check if we made it to the end of our characters.
-        // If we did, we're done!
-        if !applied_line_break {
-            break;
+    let start = narrowest(lines, bounds.clone())?;
+    let end = narrowest(fewer, start..bounds.end)?;
+    Ok((start < end).then_some(start..end))
+}
+
+/// Returns whether [`apply_newlines`] breaks `string` in the same places with font `a` as it
+/// does with font `b`.
+///
+/// Both fonts must have a width for every character of `string` that is measured, or this fails
+/// with [`LineBreakErr::MissingCharacterWidth`], even if the layouts differ before that character.
+/// Otherwise, the lines are compared as they are wrapped, stopping at the first one that starts
+/// somewhere else. An error wrapping either of those lines is returned.
+pub fn same_layout(
+    string: &str,
+    max_width: usize,
+    a: &HashMap<char, usize>,
+    b: &HashMap<char, usize>,
+) -> Result<bool, LineBreakErr> {
+    for font in [a, b] {
+        let missing = string
+            .chars()
+            .find(|&c| !is_hard_break(c) && !font.contains_key(&c));
+        if let Some(c) = missing {
+            return Err(LineBreakErr::MissingCharacterWidth(c));
         }
     }
 
-    // push in the final characters into the str
-    let s: String = chars.into_iter().map(|n| n.0).collect();
-    output.push_str(&s); // pushing the last bit in!
-    Ok(output)
+    let options = LayoutOptions::new(max_width);
+    let mut a_lines = Lines::new(string, &options, sum_chars(|_, c| a.get(&c).copied()));
+    let mut b_lines = Lines::new(string, &options, sum_chars(|_, c| b.get(&c).copied()));
+    loop {
+        match (a_lines.next().transpose()?, b_lines.next().transpose()?) {
+            (None, None) => return Ok(true),
+            (Some(a_line), Some(b_line)) if a_line.start == b_line.start => {}
+            _ => return Ok(false),
+        }
+    }
 }
 
-#[derive(Debug, thiserror::Error, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, thiserror::Error, PartialEq, Eq, Clone)]
 pub enum LineBreakErr {
-    #[error("missing character width for `{0}`")]
+    #[error("missing character width for `{0}` (U+{:04X})", *.0 as u32)]
     MissingCharacterWidth(char),
-    #[error("no legal linebreak opportunity found")]
-    NoLegalLinebreakOpportunity,
+    #[error(
+        "no place to break the {}-character run starting at byte {offset}: \"{}\"",
+        .run.chars().count(),
+        excerpt(.run)
+    )]
+    NoLegalLinebreakOpportunity {
+        /// The byte offset of the start of the run.
+        offset: usize,
+        /// The run of text which couldn't be broken.
+        run: String,
+    },
+    #[error("line {line} was wrapped when only {width} wide, leaving more than the allowed slack")]
+    UnderfullLine {
+        /// The index of the line.
+        line: usize,
+        /// The width of the line.
+        width: usize,
+    },
+    #[error("no room left on a line {max_width} wide after reserving {reserved} for indents")]
+    NoAvailableWidth {
+        /// The maximum width of the line.
+        max_width: usize,
+        /// The width taken up before any text, which is at least `max_width`.
+        reserved: usize,
+    },
+    #[error("unexpected control character U+{:04X} at byte {offset}", *.ch as u32)]
+    UnexpectedControl {
+        /// The control character.
+        ch: char,
+        /// The byte offset of the character.
+        offset: usize,
+    },
+    #[error("couldn't write the wrapped text")]
+    Write(std::fmt::Error),
+    #[error("wrapping got stuck on the line starting at byte {offset}; this is a bug")]
+    Stalled {
+        /// The byte offset of the line that ended without moving on.
+        offset: usize,
+    },
+    #[error("byte range {start}..{end} is out of bounds or not on character boundaries")]
+    InvalidRange {
+        /// The start of the range.
+        start: usize,
+        /// The end of the range.
+        end: usize,
+    },
+    #[error("the column is {width} wide, more than the maximum of {max_width}")]
+    ColumnTooWide {
+        /// The width the column needs.
+        width: usize,
+        /// The maximum width it was given.
+        max_width: usize,
+    },
+    #[error("the text needs more than the allowed {limit} lines")]
+    TooManyLines {
+        /// The maximum number of lines, from [`LayoutOptions::max_lines`].
+        limit: usize,
+    },
+    #[error("invalid options: {0}")]
+    InvalidOptions(OptionError),
+}
+
+/// Shortens text to a length that's reasonable to show in an error message.
+fn excerpt(text: &str) -> String {
+    const MAX_CHARS: usize = 20;
+
+    match text.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// Splits a string into lines at every mandatory break, without any width-based wrapping.
+///
+/// Unlike [`str::lines`], which only knows about `\n` and `\r\n`, this uses the Unicode line
+/// breaking algorithm to find hard line breaks, so it also splits on a lone `\r`, vertical tab,
+/// form feed, NEL (U+0085), LINE SEPARATOR (U+2028), and PARAGRAPH SEPARATOR (U+2029). The line
+/// terminator is not included in the yielded lines. Like [`str::lines`], a trailing terminator
+/// does not produce a final empty line.
+pub fn hard_lines(s: &str) -> impl Iterator<Item = &str> + '_ {
+    let mut start = 0;
+    linebreaks(s)
+        .filter(|&(_, op)| op == Some(BreakOpportunity::Mandatory))
+        .map(move |(end, _)| {
+            let line = &s[start..end];
+            start = end;
+
+            line.strip_suffix("\r\n")
+                .or_else(|| line.strip_suffix(is_hard_break))
+                .unwrap_or(line)
+        })
+}
+
+/// Returns whether this character's break class is [`BreakClass::Unknown`] (`XX`).
+///
+/// This is exactly the case where the Unicode Character Database gives the character no other
+/// line breaking class: either it is explicitly `XX`, as private use characters are, or it isn't
+/// listed at all. It is not the same as being unassigned, since the unassigned code points in some
+/// ranges, like most of planes 2 and 3, default to [`BreakClass::Ideographic`] instead. The layout
+/// functions don't reject these characters; they break around them as if they were
+/// [`BreakClass::Alphabetic`]. With the `no-tables` feature, no character is unknown.
+pub fn is_unknown_break_class(c: char) -> bool {
+    break_property(c as u32) == BreakClass::Unknown
+}
+
+/// Returns whether this character is a zero width joiner, whose break class is
+/// [`BreakClass::ZeroWidthJoiner`] (`ZWJ`).
+///
+/// The pair table has no entries for a joiner: the scan for break opportunities checks for one
+/// by this class itself, and never breaks after it (rule LB8a), so emoji sequences joined by it
+/// stay together. With the `no-tables` feature, no character is a joiner.
+pub fn is_zero_width_joiner(c: char) -> bool {
+    break_property(c as u32) == BreakClass::ZeroWidthJoiner
+}
+
+/// Returns true if this character causes a mandatory break after it.
+fn is_hard_break(c: char) -> bool {
+    matches!(
+        break_property(c as u32),
+        BreakClass::Mandatory
+            | BreakClass::CarriageReturn
+            | BreakClass::LineFeed
+            | BreakClass::NextLine
+    )
+}
+
+/// The number of break classes. Every class converts to a `u8` below this, so it is the size of
+/// a table indexed by class.
+pub const BREAK_CLASS_COUNT: usize = BreakClass::Unknown as usize + 1;
+
+/// Every break class, indexed by its `u8` value.
+pub const ALL_BREAK_CLASSES: [BreakClass; BREAK_CLASS_COUNT] = [
+    BK, CR, LF, CM, NL, SG, WJ, ZW, GL, SP, ZWJ, B2, BA, BB, HY, CB, CL, CP, EX, IN, NS, OP, QU,
+    IS, NU, PO, PR, SY, AI, AL, CJ, EB, EM, H2, H3, HL, ID, JL, JV, JT, RI, SA, XX,
+];
+
+const BREAK_CLASS_NAMES: [&str; BREAK_CLASS_COUNT] = [
+    "Mandatory",
+    "CarriageReturn",
+    "LineFeed",
+    "CombiningMark",
+    "NextLine",
+    "Surrogate",
+    "WordJoiner",
+    "ZeroWidthSpace",
+    "NonBreakingGlue",
+    "Space",
+    "ZeroWidthJoiner",
+    "BeforeAndAfter",
+    "After",
+    "Before",
+    "Hyphen",
+    "Contingent",
+    "ClosePunctuation",
+    "CloseParenthesis",
+    "Exclamation",
+    "Inseparable",
+    "NonStarter",
+    "OpenPunctuation",
+    "Quotation",
+    "InfixSeparator",
+    "Numeric",
+    "Postfix",
+    "Prefix",
+    "Symbol",
+    "Ambiguous",
+    "Alphabetic",
+    "ConditionalJapaneseStarter",
+    "EmojiBase",
+    "EmojiModifier",
+    "HangulLvSyllable",
+    "HangulLvtSyllable",
+    "HebrewLetter",
+    "Ideographic",
+    "HangulLJamo",
+    "HangulVJamo",
+    "HangulTJamo",
+    "RegionalIndicator",
+    "ComplexContext",
+    "Unknown",
+];
+
+/// Returns the name of a break class, the same as the name of its variant, for example
+/// `"Alphabetic"` or `"ZeroWidthJoiner"`.
+pub fn break_class_name(class: BreakClass) -> &'static str {
+    BREAK_CLASS_NAMES[class.as_u8() as usize]
+}
+
+impl BreakClass {
+    /// Returns the value this class is stored as in the break tables.
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
 }
 
+impl From<BreakClass> for u8 {
+    fn from(class: BreakClass) -> Self {
+        class.as_u8()
+    }
+}
+
+/// The error returned when converting an out-of-range `u8` into a [`BreakClass`].
+#[derive(Debug, thiserror::Error, PartialEq, Eq, Clone, Copy)]
+#[error("`{0}` is not a valid break class")]
+pub struct InvalidBreakClass(pub u8);
+
+impl TryFrom<u8> for BreakClass {
+    type Error = InvalidBreakClass;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        ALL_BREAK_CLASSES
+            .get(value as usize)
+            .copied()
+            .ok_or(InvalidBreakClass(value))
+    }
+}
+
+#[cfg(not(feature = "no-tables"))]
 fn break_property(codepoint: u32) -> BreakClass {
     let codepoint = codepoint as usize;
     match PAGE_INDICES.get(codepoint >> 8) {
-        Some(&page_idx) if page_idx & UNIFORM_PAGE != 0 => unsafe {
-            mem::transmute((page_idx & !UNIFORM_PAGE) as u8)
-        },
+        Some(&page_idx) if page_idx & UNIFORM_PAGE != 0 => {
+            BreakClass::try_from((page_idx & !UNIFORM_PAGE) as u8).unwrap_or(BreakClass::Unknown)
+        }
         Some(&page_idx) => BREAK_PROP_DATA[page_idx][codepoint & 0xFF],
         None => BreakClass::Unknown,
     }
@@ -108,52 +537,364 @@ fn break_property(codepoint: u32) -> BreakClass {
 
 /// Break opportunity type.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
-enum BreakOpportunity {
+pub enum BreakOpportunity {
     /// A line must break at this spot.
     Mandatory,
     /// A line is allowed to end at this spot.
     Allowed,
 }
 
+/// Returns an iterator over the byte offsets where a line may or must break in this string.
+///
+/// Each offset is a break before the character starting there. The end of the text is always a
+/// mandatory break, so it is never reported: every yielded offset is strictly less than
+/// `s.len()`, and no special-casing is needed to ignore a spurious break at the end.
+pub fn line_break_opportunities(
+    s: &str,
+) -> impl Iterator<Item = (usize, BreakOpportunity)> + Clone + '_ {
+    linebreaks(s)
+        .filter(move |&(i, _)| i < s.len())
+        .filter_map(|(i, op)| op.map(|op| (i, op)))
+}
+
+/// Splits this string at each of its [`line_break_opportunities`], so every piece but the last
+/// ends where a line may or must break.
+///
+/// Each piece is a word, with any whitespace after it, which is the shape other wrapping
+/// libraries split text into before fitting it to lines. For example, the `textwrap` crate can
+/// break with the Unicode rules by passing a function that maps these pieces to `Word::from` as
+/// its `WordSeparator::Custom`. Empty text has no pieces.
+pub fn break_segments(s: &str) -> impl Iterator<Item = &str> + '_ {
+    let mut start = 0;
+    line_break_opportunities(s)
+        .map(|(offset, _)| offset)
+        .chain((!s.is_empty()).then_some(s.len()))
+        .map(move |end| {
+            let segment = &s[start..end];
+            start = end;
+            segment
+        })
+}
+
+/// Returns this string with a `·` at each allowed break opportunity and a `¶` at each mandatory
+/// one, for seeing where text can break while debugging.
+///
+/// See [`debug_render_breaks_with`] for where the markers go.
+pub fn debug_render_breaks(s: &str) -> String {
+    debug_render_breaks_with(s, "·", "¶")
+}
+
+/// Like [`debug_render_breaks`], but with these markers for allowed and mandatory breaks.
+///
+/// Each marker goes at the offset [`line_break_opportunities`] reports, before the character a
+/// line may start with, so a mandatory marker comes after the hard line break that causes it.
+/// The end of the text is never marked.
+pub fn debug_render_breaks_with(s: &str, allowed: &str, mandatory: &str) -> String {
+    let mut rendered = String::with_capacity(s.len());
+    let mut start = 0;
+    for (offset, op) in line_break_opportunities(s) {
+        rendered.push_str(&s[start..offset]);
+        rendered.push_str(match op {
+            BreakOpportunity::Allowed => allowed,
+            BreakOpportunity::Mandatory => mandatory,
+        });
+        start = offset;
+    }
+    rendered.push_str(&s[start..]);
+    rendered
+}
+
+/// Where the scan for break opportunities has got to in a text, so the opportunities in the rest
+/// of it can be found without scanning it all again.
+///
+/// Whether a line may break before a character depends on more than the one before it, so the
+/// scan can't just start again partway through a text: a break after a space would be missed at
+/// the start of the text, where a line never breaks. A state from [`BreakState::after`] picks up
+/// where the text before left off instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakState(ScanState);
+
+impl BreakState {
+    /// Returns the state at the start of a text.
+    pub fn new() -> Self {
+        BreakState(SCAN_START)
+    }
+
+    /// Returns the state after `preceding`, as if it were the start of the text.
+    ///
+    /// Nothing before a hard line break affects the opportunities after it, so only the text
+    /// from the last hard line break in `preceding` is scanned.
+    pub fn after(preceding: &str) -> Self {
+        let start = preceding.rfind(is_hard_break).unwrap_or(0);
+        let mut state = Self::new();
+        state.advance(&preceding[start..]);
+        state
+    }
+
+    /// Moves the state on past `text`, which comes next in the text scanned so far.
+    pub fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            scan_step(&mut self.0, Some(break_property(c as u32)));
+        }
+    }
+
+    /// Returns the opportunities in `s`, which comes next in the text scanned so far, like
+    /// [`line_break_opportunities`].
+    ///
+    /// Offsets are relative to the start of `s`, and one at 0 is a break before its first
+    /// character. The end of `s` is the end of the text, so it is never reported.
+    pub fn opportunities(
+        self,
+        s: &str,
+    ) -> impl Iterator<Item = (usize, BreakOpportunity)> + Clone + '_ {
+        classified_linebreaks_from(s, self.0)
+            .filter(move |&(i, _, _)| i < s.len())
+            .filter_map(|(i, _, op)| op.map(|op| (i, op)))
+    }
+}
+
+impl Default for BreakState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The break opportunities of a text packed into two bits per byte, for keeping them for a large
+/// document without a `(usize, BreakOpportunity)` pair for each one.
+///
+/// Looking up the opportunity at an offset takes constant time. A text of `n` bytes takes `n / 4`
+/// bytes, however many opportunities it has.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BreakSet {
+    /// Two bits for each byte offset: the low one is set for an allowed break, the high one for a
+    /// mandatory break.
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BreakSet {
+    const PER_WORD: usize = u64::BITS as usize / 2;
+
+    /// Finds the break opportunities in `s`, the same ones as [`line_break_opportunities`].
+    pub fn new(s: &str) -> Self {
+        let mut set = BreakSet {
+            words: vec![0; s.len().div_ceil(Self::PER_WORD)],
+            len: s.len(),
+        };
+        for (offset, op) in line_break_opportunities(s) {
+            let bit = match op {
+                BreakOpportunity::Allowed => 1,
+                BreakOpportunity::Mandatory => 2,
+            };
+            set.words[offset / Self::PER_WORD] |= bit << (offset % Self::PER_WORD * 2);
+        }
+        set
+    }
+
+    /// Returns the break opportunity before the byte at `offset`, if there is one.
+    ///
+    /// Like [`line_break_opportunities`], there is never one at the end of the text, or at an
+    /// offset that isn't a character boundary.
+    pub fn get(&self, offset: usize) -> Option<BreakOpportunity> {
+        let word = self.words.get(offset / Self::PER_WORD)?;
+        match word >> (offset % Self::PER_WORD * 2) & 0b11 {
+            1 => Some(BreakOpportunity::Allowed),
+            2 => Some(BreakOpportunity::Mandatory),
+            _ => None,
+        }
+    }
+
+    /// Returns the length in bytes of the text the opportunities are for.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the text the opportunities are for is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the opportunities in order, as [`line_break_opportunities`] does.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, BreakOpportunity)> + '_ {
+        (0..self.len).filter_map(|offset| Some((offset, self.get(offset)?)))
+    }
+}
+
+/// Returns an iterator over the byte offsets of the tab characters in this string.
+///
+/// These are byte offsets, not columns: how far along the line each tab stop is depends on the
+/// font and on how the text has been wrapped. A line may break after a tab, or a run of them, as
+/// [`line_break_opportunities`] reports.
+pub fn tab_positions(s: &str) -> impl Iterator<Item = usize> + '_ {
+    s.match_indices('\t').map(|(idx, _)| idx)
+}
+
+/// Returns an iterator over the byte offset of each character boundary in this string, with the
+/// break class of the character before it and the break opportunity there.
+///
+/// This finds the opportunities and the classes they come from in one pass, for tools that show
+/// why text may break where it does. Unlike [`line_break_opportunities`], every
+/// boundary is yielded, including those where the text can't break, and the end of the text,
+/// which is always a mandatory break. The start of the text has no character before it, so it
+/// is never yielded.
+pub fn annotated_opportunities(
+    s: &str,
+) -> impl Iterator<Item = (usize, BreakClass, Option<BreakOpportunity>)> + Clone + '_ {
+    classified_linebreaks(s)
+        .scan(None, |prev, (i, class, op)| {
+            Some((i, std::mem::replace(prev, class), op))
+        })
+        .filter_map(|(i, before, op)| Some((i, before?, op)))
+}
+
+/// Counts the characters of each break class in this string, for telling what kind of text it is,
+/// like whether it is mostly CJK or has a lot of whitespace.
+///
+/// Each Unicode scalar value is counted by itself, so a base character and the combining marks
+/// on it count once each, under their own classes. Classes that don't appear aren't in the map.
+pub fn class_histogram(s: &str) -> HashMap<BreakClass, usize> {
+    let mut histogram = HashMap::new();
+    for c in s.chars() {
+        *histogram.entry(break_property(c as u32)).or_insert(0) += 1;
+    }
+    histogram
+}
+
 /// Returns an iterator over line break opportunities in the specified string.
 fn linebreaks(s: &str) -> impl Iterator<Item = (usize, Option<BreakOpportunity>)> + Clone + '_ {
-    use BreakOpportunity::{Allowed, Mandatory};
+    classified_linebreaks(s).map(|(i, _, op)| (i, op))
+}
 
-    s.char_indices()
-        .map(|(i, c)| (i, break_property(c as u32) as u8))
-        .chain(once((s.len(), eot)))
-        .scan((sot, false), |state, (i, cls)| {
-            // ZWJ is handled outside the table to reduce its size
-            let val = PAIR_TABLE[state.0 as usize][cls as usize];
-            let is_mandatory = val & MANDATORY_BREAK_BIT != 0;
-            let is_break = val & ALLOWED_BREAK_BIT != 0 && (!state.1 || is_mandatory);
-            *state = (
-                val & !(ALLOWED_BREAK_BIT | MANDATORY_BREAK_BIT),
-                cls == BreakClass::ZeroWidthJoiner as u8,
-            );
+/// Like [`linebreaks`], but also with the break class of the character at each offset, or `None`
+/// at the end of the text.
+fn classified_linebreaks(
+    s: &str,
+) -> impl Iterator<Item = (usize, Option<BreakClass>, Option<BreakOpportunity>)> + Clone + '_ {
+    classified_linebreaks_from(s, SCAN_START)
+}
 
-            Some((i, is_break, is_mandatory))
-        })
-        .map(|(i, is_break, is_mandatory)| {
-            if is_break {
-                (i, Some(if is_mandatory { Mandatory } else { Allowed }))
-            } else {
-                (i, None)
-            }
+/// Like [`classified_linebreaks`], but carrying on the scan from `state` instead of the start of
+/// the text.
+fn classified_linebreaks_from(
+    s: &str,
+    state: ScanState,
+) -> impl Iterator<Item = (usize, Option<BreakClass>, Option<BreakOpportunity>)> + Clone + '_ {
+    s.char_indices()
+        .map(|(i, c)| (i, Some(break_property(c as u32))))
+        .chain(once((s.len(), None)))
+        .scan(state, |state, (i, class)| {
+            Some((i, class, scan_step(state, class)))
         })
 }
 
+/// The state of the pair table scan: the state the table is in, and whether the last character
+/// was a zero width joiner.
+#[cfg(not(feature = "no-tables"))]
+type ScanState = (u8, bool);
+
+#[cfg(not(feature = "no-tables"))]
+const SCAN_START: ScanState = (sot, false);
+
+/// Moves the scan on to a character of break class `class`, or the end of the text if it's
+/// `None`, and returns the opportunity before it.
+#[cfg(not(feature = "no-tables"))]
+fn scan_step(state: &mut ScanState, class: Option<BreakClass>) -> Option<BreakOpportunity> {
+    let cls = class.map_or(eot, |class| class as u8);
+    // ZWJ is handled outside the table to reduce its size
+    let val = PAIR_TABLE[state.0 as usize][cls as usize];
+    let is_mandatory = val & MANDATORY_BREAK_BIT != 0;
+    let is_break = val & ALLOWED_BREAK_BIT != 0 && (!state.1 || is_mandatory);
+    *state = (
+        val & !(ALLOWED_BREAK_BIT | MANDATORY_BREAK_BIT),
+        cls == BreakClass::ZeroWidthJoiner as u8,
+    );
+
+    match (is_break, is_mandatory) {
+        (false, _) => None,
+        (true, true) => Some(BreakOpportunity::Mandatory),
+        (true, false) => Some(BreakOpportunity::Allowed),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn make_font() -> HashMap<char, usize> {
+    pub(crate) fn make_font() -> HashMap<char, usize> {
         (0..=255)
             .filter_map(char::from_u32)
             .map(|c| (c, 1))
             .collect()
     }
 
+    /// A small deterministic random number generator for property tests.
+    pub(crate) struct Rng(u64);
+
+    impl Rng {
+        const CHARS: &'static str = "abcdefghijklmnopqrstuvwxyz -,.é中\u{AD}\r\n";
+
+        pub(crate) fn new(seed: u64) -> Self {
+            Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+        }
+
+        /// Returns a number in `0..n`.
+        pub(crate) fn below(&mut self, n: usize) -> usize {
+            // xorshift64*
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as usize % n
+        }
+
+        /// Returns text made of words, runs of spaces, long tokens and hard breaks. Words may have
+        /// soft hyphens in them.
+        pub(crate) fn text(&mut self) -> String {
+            let letters: Vec<char> = Self::CHARS.chars().filter(|c| c.is_alphabetic()).collect();
+            let mut text = String::new();
+
+            for _ in 0..self.below(16) {
+                match self.below(10) {
+                    0 => text.push('\n'),
+                    1 => text.push_str("\r\n"),
+                    2 => text.push_str(&" ".repeat(1 + self.below(3))),
+                    3 => text.push_str(", "),
+                    4 => text.push('-'),
+                    kind => {
+                        let len = if kind == 5 {
+                            10 + self.below(20)
+                        } else {
+                            1 + self.below(6)
+                        };
+                        for i in 0..len {
+                            if i > 0 && self.below(8) == 0 {
+                                text.push('\u{AD}');
+                            }
+                            text.push(letters[self.below(letters.len())]);
+                        }
+                        text.push(' ');
+                    }
+                }
+            }
+
+            text
+        }
+
+        /// Returns a font covering everything [`Rng::text`] produces, with random widths.
+        pub(crate) fn font(&mut self) -> HashMap<char, usize> {
+            Self::CHARS
+                .chars()
+                .map(|c| {
+                    let width = if self.below(20) == 0 {
+                        12
+                    } else {
+                        self.below(4)
+                    };
+                    (c, width)
+                })
+                .collect()
+        }
+    }
+
     #[test]
     fn basic() {
         assert_eq!(
@@ -173,7 +914,10 @@ mod tests {
 
         assert_eq!(
             apply_newlines("Supercalifragalisticexpialidocious", 30, &make_font()).unwrap_err(),
-            LineBreakErr::NoLegalLinebreakOpportunity
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "Supercalifragalisticexpialidocious".to_string()
+            }
         );
 
         assert_eq!(
@@ -181,4 +925,716 @@ mod tests {
             LineBreakErr::MissingCharacterWidth('≤')
         );
     }
+
+    #[test]
+    fn rewrap_to_new_width() {
+        let font = make_font();
+
+        let narrow = unwrap_and_rewrap(
+            &["the quick brown fox\n", "jumps over the lazy dog"],
+            10,
+            &font,
+        )
+        .unwrap();
+        assert_eq!(
+            narrow,
+            [
+                "the quick ",
+                "brown fox\n",
+                "jumps ",
+                "over the ",
+                "lazy dog"
+            ]
+        );
+
+        // Growing pulls words back up, but never across a hard break
+        let lines: Vec<&str> = narrow.iter().map(String::as_str).collect();
+        assert_eq!(
+            unwrap_and_rewrap(&lines, 20, &font).unwrap(),
+            ["the quick brown fox\n", "jumps over the lazy ", "dog"]
+        );
+        assert_eq!(
+            unwrap_and_rewrap(&lines, 4, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 4,
+                run: "quick".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn paragraph_lines() {
+        let font = make_font();
+        assert_eq!(
+            wrap_paragraph("aa bb cc", 5, &font).unwrap(),
+            ["aa ", "bb cc"]
+        );
+        assert_eq!(wrap_paragraph("", 5, &font).unwrap(), [""]);
+        assert_eq!(
+            wrap_paragraph("aaaaaa", 5, &font).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "aaaaaa".to_string()
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic = "hard line break"]
+    fn paragraph_with_hard_break() {
+        let _ = wrap_paragraph("aa\nbb", 5, &make_font());
+    }
+
+    #[test]
+    fn trailing_hard_breaks() {
+        let font = make_font();
+        let options = LayoutOptions::new(usize::MAX);
+        for (text, lines) in [
+            ("", vec![""]),
+            ("a", vec!["a"]),
+            ("a\n", vec!["a", ""]),
+            ("a\n\n", vec!["a", "", ""]),
+        ] {
+            let result = layout_detailed(text, &options, &font).unwrap();
+            assert_eq!(result.lines().collect::<Vec<_>>(), lines, "{text:?}");
+            assert_eq!(result.line_starts.len(), lines.len(), "{text:?}");
+            assert_eq!(
+                result.text.matches('\n').count() + 1,
+                lines.len(),
+                "{text:?}"
+            );
+            assert_eq!(count_lines(text, &options, &font).unwrap(), lines.len());
+            assert_eq!(
+                layout_spans(text, &options, &font).unwrap().len(),
+                lines.len()
+            );
+
+            let iterated: Vec<String> = wrap_iter(text, usize::MAX, &font)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(iterated, lines, "{text:?}");
+            let tagged = wrap_tagged(text, usize::MAX, &font).unwrap();
+            assert_eq!(
+                tagged.iter().map(|&(line, _)| line).collect::<Vec<_>>(),
+                lines
+            );
+            assert_eq!(tagged.last().unwrap().1, BreakKind::EndOfText);
+        }
+    }
+
+    #[test]
+    fn segments() {
+        assert_eq!(break_segments("").count(), 0);
+        assert_eq!(break_segments("word").collect::<Vec<_>>(), ["word"]);
+        assert_eq!(
+            break_segments("Hello, world!\nbye  now").collect::<Vec<_>>(),
+            ["Hello, ", "world!\n", "bye  ", "now"]
+        );
+        #[cfg(not(feature = "no-tables"))]
+        assert_eq!(
+            break_segments("in 中文").collect::<Vec<_>>(),
+            ["in ", "中", "文"]
+        );
+    }
+
+    #[test]
+    fn rendered_breaks() {
+        assert_eq!(debug_render_breaks(""), "");
+        assert_eq!(debug_render_breaks("word"), "word");
+        assert_eq!(debug_render_breaks("aa bb-cc\ndd "), "aa ·bb-·cc\n¶dd ");
+        assert_eq!(
+            debug_render_breaks_with("a b\r\nc", "|", "<br>"),
+            "a |b\r\n<br>c"
+        );
+    }
+
+    #[test]
+    fn tagged_lines() {
+        use BreakKind::{EndOfText, Hard, Soft};
+
+        let font = make_font();
+        assert_eq!(
+            wrap_tagged("aaa bbb ccc\r\nddd\n\neee fff", 4, &font).unwrap(),
+            [
+                ("aaa ", Soft),
+                ("bbb ", Soft),
+                ("ccc", Hard),
+                ("ddd", Hard),
+                ("", Hard),
+                ("eee ", Soft),
+                ("fff", EndOfText),
+            ]
+        );
+        assert_eq!(
+            wrap_tagged("a\n", 4, &font).unwrap(),
+            [("a", Hard), ("", EndOfText)]
+        );
+        assert_eq!(wrap_tagged("", 4, &font).unwrap(), [("", EndOfText)]);
+        assert!(wrap_tagged("aa bbbbbb", 4, &font).is_err());
+    }
+
+    #[test]
+    fn owned_lines() {
+        let font = make_font();
+
+        let lines: Vec<String> = wrap_iter("aaa bbb ccc\r\nddd", 4, &font)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, ["aaa ", "bbb ", "ccc", "ddd"]);
+        assert_eq!(
+            wrap_iter("a\n", 4, &font).collect::<Result<Vec<_>, _>>(),
+            Ok(vec!["a".to_string(), String::new()])
+        );
+        assert_eq!(wrap_iter("", 4, &font).count(), 1);
+
+        for text in ["aa bb cc dd\nee", "x\n\ny y y\n"] {
+            let lines = wrap_iter(text, 5, &font)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(lines.join("\n"), apply_newlines(text, 5, &font).unwrap());
+        }
+
+        // Lines before an error are still yielded
+        let mut lines = wrap_iter("aa bbbbbb", 4, &font);
+        assert_eq!(lines.next(), Some(Ok("aa ".to_string())));
+        assert!(matches!(
+            lines.next(),
+            Some(Err(LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 3,
+                ..
+            }))
+        ));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn annotated() {
+        use BreakClass::*;
+
+        assert_eq!(annotated_opportunities("").count(), 0);
+        assert_eq!(
+            annotated_opportunities("a b\n").collect::<Vec<_>>(),
+            [
+                (1, Alphabetic, None),
+                (2, Space, Some(BreakOpportunity::Allowed)),
+                (3, Alphabetic, None),
+                (4, LineFeed, Some(BreakOpportunity::Mandatory)),
+            ]
+        );
+
+        // The same opportunities as on their own
+        let text = "aa-bb \r\ncc\u{2028}";
+        assert!(annotated_opportunities(text)
+            .filter_map(|(i, _, op)| Some((i, op?)))
+            .filter(|&(i, _)| i < text.len())
+            .eq(line_break_opportunities(text)));
+    }
+
+    #[test]
+    fn histogram() {
+        use BreakClass::*;
+
+        assert!(class_histogram("").is_empty());
+        assert_eq!(
+            class_histogram("ab c\n"),
+            HashMap::from([(Alphabetic, 3), (Space, 1), (LineFeed, 1)])
+        );
+        #[cfg(not(feature = "no-tables"))]
+        assert_eq!(
+            class_histogram("中文, e\u{301}"),
+            HashMap::from([
+                (Ideographic, 2),
+                (InfixSeparator, 1),
+                (Space, 1),
+                (Alphabetic, 1),
+                (CombiningMark, 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn stable_widths() {
+        let font = make_font();
+        assert_eq!(stable_up_to("aa bb cc", 3, &font).unwrap(), 4);
+        assert_eq!(stable_up_to("aa bb cc", 5, &font).unwrap(), 5);
+        assert_eq!(stable_up_to("aa bb cc", 6, &font).unwrap(), 7);
+        assert_eq!(stable_up_to("aa bb cc", 8, &font).unwrap(), usize::MAX);
+        assert_eq!(stable_up_to("aa\nbb", 2, &font).unwrap(), usize::MAX);
+
+        for seed in 0..200 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let width = 1 + rng.below(20);
+            let Ok(stable) = stable_up_to(&text, width, &font) else {
+                continue;
+            };
+
+            let expected = apply_newlines(&text, width, &font).unwrap();
+            for wider in width..=stable.min(width + 40) {
+                assert_eq!(apply_newlines(&text, wider, &font).unwrap(), expected);
+            }
+            if stable != usize::MAX {
+                assert_ne!(apply_newlines(&text, stable + 1, &font).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn widths_for_line_counts() {
+        let font = make_font();
+        let text = "aa bb cc dd";
+        assert_eq!(
+            width_range_for_lines(text, 4, &font, 0..20).unwrap(),
+            Some(3..5)
+        );
+        assert_eq!(
+            width_range_for_lines(text, 3, &font, 0..20).unwrap(),
+            Some(5..6)
+        );
+        assert_eq!(width_range_for_lines(text, 5, &font, 0..20).unwrap(), None);
+        assert_eq!(
+            width_range_for_lines(text, 2, &font, 0..20).unwrap(),
+            Some(6..11)
+        );
+        assert_eq!(
+            width_range_for_lines(text, 2, &font, 0..8).unwrap(),
+            Some(6..8)
+        );
+        assert_eq!(
+            width_range_for_lines(text, 1, &font, 0..20).unwrap(),
+            Some(11..20)
+        );
+        assert_eq!(width_range_for_lines(text, 0, &font, 0..20).unwrap(), None);
+        assert_eq!(
+            width_range_for_lines("a\u{1F600}", 1, &font, 0..5).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('\u{1F600}')
+        );
+
+        for seed in 0..200 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let counts: Vec<Option<usize>> = (0..40)
+                .map(|width| count_lines(&text, &LayoutOptions::new(width), &font).ok())
+                .collect();
+            for lines in 0..8 {
+                let expected: Vec<usize> = (0..40).filter(|&w| counts[w] == Some(lines)).collect();
+                let range = width_range_for_lines(&text, lines, &font, 0..40).unwrap();
+                assert_eq!(
+                    range.unwrap_or_default().collect::<Vec<_>>(),
+                    expected,
+                    "{text:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn same_layouts() {
+        let font = make_font();
+        let mut wide_b = make_font();
+        wide_b.insert('b', 2);
+
+        assert!(same_layout("aa bb cc", 6, &font, &font).unwrap());
+        // "aa bb " is 8 wide with the wider b, which still fits
+        assert!(same_layout("aa bb cccc", 8, &font, &wide_b).unwrap());
+        assert!(!same_layout("aa bb cccc", 6, &font, &wide_b).unwrap());
+        // The first lines start in the same place, but there are more of them
+        assert!(!same_layout("aa bb", 5, &font, &wide_b).unwrap());
+
+        let mut missing = make_font();
+        missing.remove(&'c');
+        assert_eq!(
+            same_layout("aa bb cc", 6, &font, &missing).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('c')
+        );
+        assert_eq!(
+            same_layout("aaaa bb", 3, &font, &wide_b).unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "aaaa".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn hard_breaks_are_never_measured() {
+        let mut font = make_font();
+        for c in [
+            '\n', '\r', '\u{b}', '\u{c}', '\u{85}', '\u{2028}', '\u{2029}',
+        ] {
+            font.insert(c, 999);
+        }
+        let text = "aa\nbb\r\ncc\u{b}dd\u{c}ee\u{85}ff\u{2028}gg\u{2029}hh";
+
+        assert_eq!(apply_newlines(text, 2, &font).unwrap(), text);
+        assert_eq!(measure_width(text, &font).unwrap().widest_line, 2);
+        assert_eq!(measure_width_iter(text.chars(), &font).unwrap(), 16);
+        assert_eq!(min_unbreakable_width(text, &font).unwrap(), 2);
+        assert_eq!(width_prefix_sums("a\n", &font).unwrap(), [0, 1, 1]);
+        assert_eq!(offset_at("a\nb", 999, &font).unwrap(), 1);
+    }
+
+    #[test]
+    fn nul_is_not_a_terminator() {
+        let mut font = make_font();
+        font.insert('\0', 0);
+        let text = "hello\0world this is long enough to wrap";
+
+        let wrapped = apply_newlines(text, 12, &font).unwrap();
+        assert_eq!(wrapped, "hello\0world \nthis is \nlong enough \nto wrap");
+        assert_eq!(
+            count_lines(text, &LayoutOptions::new(12), &font).unwrap(),
+            4
+        );
+        assert_eq!(
+            wrap_iter(text, 12, &font)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            ["hello\0world ", "this is ", "long enough ", "to wrap"]
+        );
+
+        // Without a width for it, NUL is an error like any other character
+        font.remove(&'\0');
+        assert_eq!(
+            apply_newlines(text, 12, &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('\0')
+        );
+    }
+
+    #[test]
+    fn hard_lines_split() {
+        assert_eq!(hard_lines("").count(), 0);
+        assert_eq!(hard_lines("a").collect::<Vec<_>>(), ["a"]);
+        assert_eq!(hard_lines("a\n").collect::<Vec<_>>(), ["a"]);
+        assert_eq!(hard_lines("a\n\nb").collect::<Vec<_>>(), ["a", "", "b"]);
+        assert_eq!(
+            hard_lines("one\r\ntwo\rthree\u{85}four\u{2028}five\u{2029}six").collect::<Vec<_>>(),
+            ["one", "two", "three", "four", "five", "six"]
+        );
+
+        // Soft break opportunities never split
+        assert_eq!(
+            hard_lines("no hard breaks here").collect::<Vec<_>>(),
+            ["no hard breaks here"]
+        );
+    }
+
+    #[test]
+    fn break_class_u8_round_trip() {
+        for value in 0..ALL_BREAK_CLASSES.len() as u8 {
+            let class = BreakClass::try_from(value).unwrap();
+            assert_eq!(class.as_u8(), value);
+            assert_eq!(u8::from(class), value);
+        }
+
+        assert_eq!(BreakClass::try_from(0).unwrap(), BreakClass::Mandatory);
+        assert_eq!(BreakClass::try_from(42).unwrap(), BreakClass::Unknown);
+        assert_eq!(BreakClass::try_from(43), Err(InvalidBreakClass(43)));
+        assert_eq!(ALL_BREAK_CLASSES.len(), BREAK_CLASS_COUNT);
+        assert_eq!(
+            BreakClass::try_from(BREAK_CLASS_COUNT as u8),
+            Err(InvalidBreakClass(BREAK_CLASS_COUNT as u8))
+        );
+        assert_eq!(BreakClass::try_from(eot), Err(InvalidBreakClass(eot)));
+    }
+
+    #[test]
+    fn break_class_names() {
+        assert_eq!(break_class_name(BreakClass::Alphabetic), "Alphabetic");
+        assert_eq!(break_class_name(BreakClass::Space), "Space");
+        for class in ALL_BREAK_CLASSES {
+            assert_eq!(break_class_name(class), format!("{:?}", class));
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn unknown_break_class() {
+        assert!(!is_unknown_break_class('a'));
+        assert!(!is_unknown_break_class('中'));
+        // Private use
+        assert!(is_unknown_break_class('\u{E000}'));
+        assert!(is_unknown_break_class('\u{10FFFD}'));
+        // Unassigned, but only some ranges default to XX
+        assert!(is_unknown_break_class('\u{378}'));
+        assert!(!is_unknown_break_class('\u{3FFFD}'));
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn zero_width_joiners() {
+        assert!(is_zero_width_joiner('\u{200D}'));
+        assert!(!is_zero_width_joiner('\u{200C}'));
+        assert!(!is_zero_width_joiner('a'));
+
+        // Nothing breaks after a joiner, even before a character that would otherwise allow it
+        let family = "👩\u{200D}👧";
+        assert_eq!(line_break_opportunities(family).count(), 0);
+        assert_eq!(line_break_opportunities("👩👧").count(), 1);
+    }
+
+    #[test]
+    fn tabs() {
+        assert_eq!(tab_positions("").count(), 0);
+        assert_eq!(tab_positions("a\tbé\t\tc").collect::<Vec<_>>(), [1, 5, 6]);
+
+        // Lines may break after a tab, or a run of them
+        assert_eq!(
+            line_break_opportunities("a\tb\t\tc").collect::<Vec<_>>(),
+            [
+                (2, BreakOpportunity::Allowed),
+                (5, BreakOpportunity::Allowed)
+            ]
+        );
+    }
+
+    #[test]
+    fn opportunities_skip_end_of_text() {
+        use BreakOpportunity::{Allowed, Mandatory};
+
+        assert_eq!(line_break_opportunities("").count(), 0);
+        assert_eq!(line_break_opportunities("word").count(), 0);
+        assert_eq!(
+            line_break_opportunities("a b\nc").collect::<Vec<_>>(),
+            [(2, Allowed), (4, Mandatory)]
+        );
+        assert_eq!(
+            line_break_opportunities("a b\n").collect::<Vec<_>>(),
+            [(2, Allowed)]
+        );
+    }
+
+    #[test]
+    fn packed_opportunities() {
+        let set = BreakSet::new("ab cd\né-f");
+        assert_eq!(set.len(), 10);
+        assert_eq!(set.get(3), Some(BreakOpportunity::Allowed));
+        assert_eq!(set.get(6), Some(BreakOpportunity::Mandatory));
+        assert_eq!(set.get(4), None);
+        // Inside a character, at the end and past it
+        assert_eq!(set.get(7), None);
+        assert_eq!(set.get(10), None);
+        assert_eq!(set.get(1000), None);
+        assert!(BreakSet::new("").is_empty());
+
+        let mut rng = Rng::new(0);
+        for _ in 0..200 {
+            let text = rng.text();
+            let set = BreakSet::new(&text);
+            assert_eq!(
+                set.iter().collect::<Vec<_>>(),
+                line_break_opportunities(&text).collect::<Vec<_>>(),
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn resumed_scans() {
+        use BreakOpportunity::Allowed;
+
+        // Starting again after a space would lose the break after it
+        assert_eq!(BreakState::new().opportunities("b").count(), 0);
+        assert_eq!(
+            BreakState::after("a ")
+                .opportunities("b")
+                .collect::<Vec<_>>(),
+            [(0, Allowed)]
+        );
+        // Or a regional indicator, which pairs with the one before it
+        #[cfg(not(feature = "no-tables"))]
+        assert_eq!(
+            BreakState::after("🇫")
+                .opportunities("🇷🇩🇪")
+                .collect::<Vec<_>>(),
+            [(4, Allowed)]
+        );
+
+        for seed in 0..300 {
+            let mut rng = Rng::new(seed);
+            let text = format!("{}{}", rng.text(), rng.text());
+            let split = rng.below(text.len() + 1);
+            if !text.is_char_boundary(split) {
+                continue;
+            }
+
+            let expected: Vec<_> = line_break_opportunities(&text)
+                .filter(|&(offset, _)| offset >= split)
+                .map(|(offset, op)| (offset - split, op))
+                .collect();
+            let state = BreakState::after(&text[..split]);
+            let resumed: Vec<_> = state.opportunities(&text[split..]).collect();
+            assert_eq!(resumed, expected, "{text:?} at {split}");
+
+            // Advancing through the whole text in pieces gets to the same state
+            let mid = (0..=split / 2)
+                .rev()
+                .find(|&idx| text.is_char_boundary(idx))
+                .unwrap();
+            let mut advanced = BreakState::default();
+            advanced.advance(&text[..mid]);
+            advanced.advance(&text[mid..split]);
+            assert_eq!(advanced, state);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn regional_indicator_pairs() {
+        use BreakOpportunity::Allowed;
+
+        // Flags are pairs of regional indicators, and a line may only break between two flags
+        let flags = "🇫🇷🇩🇪🇯🇵";
+        assert_eq!(
+            line_break_opportunities(flags).collect::<Vec<_>>(),
+            [(8, Allowed), (16, Allowed)]
+        );
+        // An odd one out stands alone, after the pairs before it
+        assert_eq!(
+            line_break_opportunities("🇫🇷🇩🇪🇯").collect::<Vec<_>>(),
+            [(8, Allowed), (16, Allowed)]
+        );
+        // And the count starts again after anything else
+        assert_eq!(
+            line_break_opportunities("🇫 🇷🇩🇪").collect::<Vec<_>>(),
+            [(5, Allowed), (13, Allowed)]
+        );
+
+        let font = crate::monospace_font(1, flags.chars());
+        for max_width in 2..6 {
+            let opts = LayoutOptions {
+                overflow: Overflow::Break,
+                ..LayoutOptions::new(max_width)
+            };
+            let wrapped = layout(flags, &opts, &font).unwrap();
+            assert!(wrapped.lines().all(|line| line.chars().count() % 2 == 0));
+            assert_eq!(wrapped.replace('\n', ""), flags);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-tables"))]
+    fn zwj_at_boundaries() {
+        use BreakOpportunity::Mandatory;
+
+        // A joiner never allows a break next to it, even at the ends of the text
+        assert_eq!(
+            linebreaks("\u{200D}a").collect::<Vec<_>>(),
+            [(0, None), (3, None), (4, Some(Mandatory))]
+        );
+        assert_eq!(
+            linebreaks("a\u{200D}").collect::<Vec<_>>(),
+            [(0, None), (1, None), (4, Some(Mandatory))]
+        );
+        assert_eq!(
+            linebreaks("a\u{200D}\u{200D}b").collect::<Vec<_>>(),
+            [
+                (0, None),
+                (1, None),
+                (4, None),
+                (7, None),
+                (8, Some(Mandatory))
+            ]
+        );
+
+        // And the end of the text is still a break after one
+        assert_eq!(
+            linebreaks("\u{200D}").collect::<Vec<_>>(),
+            [(0, None), (3, Some(Mandatory))]
+        );
+
+        let mut font = make_font();
+        font.insert('\u{200D}', 0);
+        for text in [
+            "\u{200D}a",
+            "a\u{200D}",
+            "a\u{200D}\u{200D}b",
+            "\u{200D}\n\u{200D}",
+        ] {
+            let opts = LayoutOptions {
+                overflow: Overflow::Break,
+                ..LayoutOptions::new(1)
+            };
+            let result = layout(text, &opts, &font).unwrap();
+            assert_eq!(result.replace('\n', ""), text.replace('\n', ""));
+        }
+    }
+
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            LineBreakErr::MissingCharacterWidth('≤').to_string(),
+            "missing character width for `≤` (U+2264)"
+        );
+        assert_eq!(
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 102,
+                run: "Supercalifragalisticexpialidocious".to_string()
+            }
+            .to_string(),
+            "no place to break the 34-character run starting at byte 102: \"Supercalifragalistic…\""
+        );
+        assert_eq!(
+            LineBreakErr::NoLegalLinebreakOpportunity {
+                offset: 0,
+                run: "short".to_string()
+            }
+            .to_string(),
+            "no place to break the 5-character run starting at byte 0: \"short\""
+        );
+        assert_eq!(
+            LineBreakErr::UnderfullLine { line: 3, width: 12 }.to_string(),
+            "line 3 was wrapped when only 12 wide, leaving more than the allowed slack"
+        );
+        assert_eq!(
+            LineBreakErr::NoAvailableWidth {
+                max_width: 4,
+                reserved: 4
+            }
+            .to_string(),
+            "no room left on a line 4 wide after reserving 4 for indents"
+        );
+        assert_eq!(
+            LineBreakErr::InvalidRange { start: 3, end: 1 }.to_string(),
+            "byte range 3..1 is out of bounds or not on character boundaries"
+        );
+        assert_eq!(
+            LineBreakErr::UnexpectedControl {
+                ch: '\u{7}',
+                offset: 4
+            }
+            .to_string(),
+            "unexpected control character U+0007 at byte 4"
+        );
+        assert_eq!(
+            LineBreakErr::Write(std::fmt::Error).to_string(),
+            "couldn't write the wrapped text"
+        );
+        assert_eq!(
+            LineBreakErr::Stalled { offset: 2 }.to_string(),
+            "wrapping got stuck on the line starting at byte 2; this is a bug"
+        );
+        assert_eq!(
+            LineBreakErr::ColumnTooWide {
+                width: 12,
+                max_width: 10
+            }
+            .to_string(),
+            "the column is 12 wide, more than the maximum of 10"
+        );
+        assert_eq!(
+            LineBreakErr::TooManyLines { limit: 100 }.to_string(),
+            "the text needs more than the allowed 100 lines"
+        );
+        assert_eq!(
+            LineBreakErr::InvalidOptions(OptionError::EmptyLineBreak {
+                option: "soft_line_break"
+            })
+            .to_string(),
+            "invalid options: soft_line_break is empty, so the lines it ends would run together"
+        );
+    }
 }
@@ -0,0 +1,535 @@
+//! Width measurement helpers.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::{
+    annotated_opportunities, hard_lines, is_hard_break, linebreaks, BreakClass, BreakOpportunity,
+    LineBreakErr,
+};
+
+/// The width of some text, as returned by [`measure_width`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextWidth {
+    /// The width of every character added up, as if the text were all on one line.
+    pub total: usize,
+    /// The width of the widest line between hard line breaks.
+    pub widest_line: usize,
+}
+
+/// Measures `text` without wrapping it.
+///
+/// Hard line break characters have no width, and split the text into the lines that
+/// [`TextWidth::widest_line`] is taken over.
+#[must_use = "measuring has no side effects"]
+pub fn measure_width(text: &str, font: &HashMap<char, usize>) -> Result<TextWidth, LineBreakErr> {
+    let mut width = TextWidth::default();
+    let mut line_width = 0;
+
+    for c in text.chars() {
+        if is_hard_break(c) {
+            line_width = 0;
+            continue;
+        }
+
+        let char_width = char_width(c, font)?;
+        width.total += char_width;
+        line_width += char_width;
+        width.widest_line = width.widest_line.max(line_width);
+    }
+
+    Ok(width)
+}
+
+/// Adds up the widths of a stream of characters, without collecting them into a string first.
+///
+/// Like [`TextWidth::total`], hard line break characters have no width.
+#[must_use = "measuring has no side effects"]
+pub fn measure_width_iter(
+    chars: impl Iterator<Item = char>,
+    font: &HashMap<char, usize>,
+) -> Result<usize, LineBreakErr> {
+    chars
+        .filter(|&c| !is_hard_break(c))
+        .try_fold(0, |width, c| Ok(width + char_width(c, font)?))
+}
+
+/// Measures the byte range `range` of `text`, such as a selection within a line.
+///
+/// Widths are the same as [`measure_width_iter`] over those characters, so hard line breaks have
+/// no width. A range that is out of bounds or doesn't fall on character boundaries is reported as
+/// [`LineBreakErr::InvalidRange`].
+#[must_use = "measuring has no side effects"]
+pub fn measure_range(
+    text: &str,
+    range: Range<usize>,
+    font: &HashMap<char, usize>,
+) -> Result<usize, LineBreakErr> {
+    let slice = text.get(range.clone()).ok_or(LineBreakErr::InvalidRange {
+        start: range.start,
+        end: range.end,
+    })?;
+    measure_width_iter(slice.chars(), font)
+}
+
+/// A break opportunity and how wide the text before it is, as returned by [`analyze`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakInfo {
+    /// The byte offset of the opportunity.
+    pub offset: usize,
+    /// Whether the text may or must break here.
+    pub opportunity: BreakOpportunity,
+    /// The break class of the character before the opportunity.
+    pub class: BreakClass,
+    /// The width of the text since the previous opportunity, including any spaces at its end,
+    /// which is how much room it needs on a line.
+    pub run_width: usize,
+    /// The width of the text since the previous mandatory break, so the width of the line if
+    /// it were never wrapped, up to here.
+    pub line_width: usize,
+}
+
+/// Returns every break opportunity in `s` with the widths of the text before it, for tools that
+/// show how text can wrap.
+///
+/// This is [`analyze_iter`] collected into a `Vec`. As in [`annotated_opportunities`], the end of
+/// the text is always a mandatory break, and hard line breaks have no width.
+pub fn analyze(s: &str, font: &HashMap<char, usize>) -> Result<Vec<BreakInfo>, LineBreakErr> {
+    analyze_iter(s, font).collect()
+}
+
+/// Like [`analyze`], but yields each opportunity as it is found, without collecting them.
+///
+/// The text is scanned once, as the iterator is advanced. A character missing from the font is
+/// reported where the scan reaches it, in place of the opportunity after it, and ends the
+/// iterator.
+pub fn analyze_iter<'a>(
+    s: &'a str,
+    font: &'a HashMap<char, usize>,
+) -> impl Iterator<Item = Result<BreakInfo, LineBreakErr>> + 'a {
+    let mut run_width = 0;
+    let mut line_width = 0;
+    s.chars()
+        .zip(annotated_opportunities(s))
+        .scan(false, move |failed, (c, (offset, class, break_op))| {
+            if *failed {
+                return None;
+            }
+            if !is_hard_break(c) {
+                match char_width(c, font) {
+                    Ok(width) => {
+                        run_width += width;
+                        line_width += width;
+                    }
+                    Err(err) => {
+                        *failed = true;
+                        return Some(Some(Err(err)));
+                    }
+                }
+            }
+
+            let Some(opportunity) = break_op else {
+                return Some(None);
+            };
+            let info = BreakInfo {
+                offset,
+                opportunity,
+                class,
+                run_width,
+                line_width,
+            };
+            run_width = 0;
+            if opportunity == BreakOpportunity::Mandatory {
+                line_width = 0;
+            }
+            Some(Some(Ok(info)))
+        })
+        .flatten()
+}
+
+/// Looks up the width of a single character.
+fn char_width(c: char, font: &HashMap<char, usize>) -> Result<usize, LineBreakErr> {
+    font.get(&c)
+        .copied()
+        .ok_or(LineBreakErr::MissingCharacterWidth(c))
+}
+
+/// Returns whether every line of `text` is at most `max_width` wide, so it needs no wrapping.
+#[must_use = "measuring has no side effects"]
+pub fn fits(
+    text: &str,
+    max_width: usize,
+    font: &HashMap<char, usize>,
+) -> Result<bool, LineBreakErr> {
+    measure_width(text, font).map(|width| width.widest_line <= max_width)
+}
+
+/// Returns the width of the widest line of text that has already been wrapped, such as the
+/// output of [`apply_newlines`](crate::apply_newlines), for shrinking a container to fit it.
+///
+/// Lines are split at every hard line break, and measured without the whitespace at their ends,
+/// which wrapping leaves at the end of each wrapped line but takes up no visible room. That
+/// whitespace doesn't need widths in the font.
+#[must_use = "measuring has no side effects"]
+pub fn content_width(wrapped: &str, font: &HashMap<char, usize>) -> Result<usize, LineBreakErr> {
+    hard_lines(wrapped).try_fold(0, |widest, line| {
+        let width = measure_width_iter(line.trim_end().chars(), font)?;
+        Ok(widest.max(width))
+    })
+}
+
+/// Returns the width of the widest run of `string` with no break opportunity inside it.
+///
+/// This is the narrowest `max_width` that `string` can be wrapped to without
+/// [`Overflow::Break`](crate::Overflow::Break) or an error: any narrower, and that run doesn't fit
+/// on a line by itself. The spaces at the end of a run count towards its width, as they do when
+/// wrapping.
+#[must_use = "measuring has no side effects"]
+pub fn min_unbreakable_width(
+    string: &str,
+    font: &HashMap<char, usize>,
+) -> Result<usize, LineBreakErr> {
+    let mut widest = 0;
+    let mut run_width = 0;
+
+    for (c, (_, break_op)) in string.chars().zip(linebreaks(string)) {
+        if break_op.is_some() {
+            run_width = 0;
+        }
+        if !is_hard_break(c) {
+            run_width += char_width(c, font)?;
+        }
+        widest = widest.max(run_width);
+    }
+
+    Ok(widest)
+}
+
+/// Returns the byte offset of the character boundary in `line` nearest to `x`.
+///
+/// Widths are accumulated from the start of the line, and `x` is rounded to whichever side of
+/// the glyph it falls on is closer, with the exact middle rounding to the right. Positions past
+/// the end of the line return the offset of the end of the line, which stops before any hard
+/// line break.
+#[must_use = "measuring has no side effects"]
+pub fn offset_at(line: &str, x: usize, font: &HashMap<char, usize>) -> Result<usize, LineBreakErr> {
+    let mut current_width = 0;
+
+    for (offset, c) in line.char_indices() {
+        if is_hard_break(c) {
+            return Ok(offset);
+        }
+
+        let width = char_width(c, font)?;
+        // Closer to the left of the glyph, with the middle rounding right
+        if x < current_width || x - current_width < width.div_ceil(2) {
+            return Ok(offset);
+        }
+        current_width += width;
+    }
+
+    Ok(line.len())
+}
+
+/// Pads a column of numbers with spaces so their decimal points line up, with the column
+/// right-aligned in `max_width`.
+///
+/// The point is the first `.` in each value. A value without one is aligned as if it came right
+/// after the value, so `"12"` lines up with the `12` of `"12.5"`. Each value is measured with
+/// [`measure_width_iter`], and padded on the left to line up its point and on the right to line
+/// up the end of the column. If the column is wider than `max_width`, that's an error.
+///
+/// The padding is whole spaces, so the points only line up exactly when the widths being made
+/// up are multiples of the width of a space, as in a font with tabular figures. Otherwise each
+/// value is padded as far as it can be without going over.
+pub fn align_decimals(
+    values: &[&str],
+    max_width: usize,
+    font: &HashMap<char, usize>,
+) -> Result<Vec<String>, LineBreakErr> {
+    let parts = values
+        .iter()
+        .map(|value| {
+            let (whole, fraction) = value.split_at(value.find('.').unwrap_or(value.len()));
+            Ok((
+                measure_width_iter(whole.chars(), font)?,
+                measure_width_iter(fraction.chars(), font)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, LineBreakErr>>()?;
+
+    let widest_whole = parts.iter().map(|&(whole, _)| whole).max().unwrap_or(0);
+    let widest_fraction = parts
+        .iter()
+        .map(|&(_, fraction)| fraction)
+        .max()
+        .unwrap_or(0);
+    let width = widest_whole + widest_fraction;
+    if width > max_width {
+        return Err(LineBreakErr::ColumnTooWide { width, max_width });
+    }
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Spaces with no width can't pad anything
+    let space = char_width(' ', font)?;
+    let pad = |width: usize| " ".repeat(width.checked_div(space).unwrap_or(0));
+    Ok(values
+        .iter()
+        .zip(parts)
+        .map(|(value, (whole, fraction))| {
+            let left = pad(max_width - width + widest_whole - whole);
+            let right = pad(widest_fraction - fraction);
+            format!("{left}{value}{right}")
+        })
+        .collect())
+}
+
+/// Returns the cumulative width of `line` at each of its character boundaries.
+///
+/// The result has one more entry than `line` has characters: the first is always `0` and the
+/// last is the width of the whole line. As elsewhere, hard line break characters have no width.
+/// Since the sums never decrease, positions can be looked up with a binary search.
+#[must_use = "measuring has no side effects"]
+pub fn width_prefix_sums(
+    line: &str,
+    font: &HashMap<char, usize>,
+) -> Result<Vec<usize>, LineBreakErr> {
+    let mut sums = Vec::with_capacity(line.len() + 1);
+    sums.push(0);
+
+    let mut current_width = 0;
+    for c in line.chars() {
+        if !is_hard_break(c) {
+            current_width += char_width(c, font)?;
+        }
+        sums.push(current_width);
+    }
+
+    Ok(sums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::make_font;
+
+    #[test]
+    fn measure_lines() {
+        let mut font = make_font();
+        font.insert('W', 4);
+
+        assert_eq!(measure_width("", &font).unwrap(), TextWidth::default());
+        assert_eq!(
+            measure_width("aW a", &font).unwrap(),
+            TextWidth {
+                total: 7,
+                widest_line: 7
+            }
+        );
+        assert_eq!(
+            measure_width("aaa\r\nW\n", &font).unwrap(),
+            TextWidth {
+                total: 7,
+                widest_line: 4
+            }
+        );
+        assert_eq!(
+            measure_width("a\u{2028}≤", &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+
+        assert_eq!(measure_width_iter("aaa\r\nW".chars(), &font).unwrap(), 7);
+        assert_eq!(
+            measure_width_iter(['a', 'W'].into_iter(), &font).unwrap(),
+            5
+        );
+        assert_eq!(
+            measure_width_iter("a≤".chars(), &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+
+        assert!(fits("aaa\nW", 4, &font).unwrap());
+        assert!(!fits("aaa\nW", 3, &font).unwrap());
+        assert!(fits("", 0, &font).unwrap());
+    }
+
+    #[test]
+    fn content_widths() {
+        let mut font = make_font();
+        font.insert('W', 4);
+        font.remove(&'\u{3000}');
+
+        assert_eq!(content_width("", &font).unwrap(), 0);
+        assert_eq!(content_width("aa \nW   \naaa", &font).unwrap(), 4);
+        // Trailing whitespace doesn't count, or need a width
+        assert_eq!(content_width("aaa   \naa\u{3000}", &font).unwrap(), 3);
+        assert_eq!(content_width("a\u{2028}aaa\r\n", &font).unwrap(), 3);
+        assert_eq!(
+            content_width("a\n\u{3000}a", &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('\u{3000}')
+        );
+
+        let text = "aaaa bb ccc dddddd";
+        let wrapped = crate::apply_newlines(text, 9, &font).unwrap();
+        assert_eq!(content_width(&wrapped, &font).unwrap(), 7);
+    }
+
+    #[test]
+    fn aligned_decimals() {
+        let font = make_font();
+        assert_eq!(
+            align_decimals(&["3.14", "12", "100.5", ".25"], 8, &font).unwrap(),
+            ["    3.14", "   12   ", "  100.5 ", "     .25"]
+        );
+        assert_eq!(
+            align_decimals(&["1.5", "2.5"], 3, &font).unwrap(),
+            ["1.5", "2.5"]
+        );
+        assert_eq!(align_decimals(&[], 0, &font).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            align_decimals(&["3.14", "100.5"], 5, &font).unwrap_err(),
+            LineBreakErr::ColumnTooWide {
+                width: 6,
+                max_width: 5
+            }
+        );
+
+        // Padding is in whole spaces, so with wide spaces it only goes as far as it can
+        let mut font = make_font();
+        font.insert(' ', 2);
+        assert_eq!(
+            align_decimals(&["1.0", "10.0", "100"], 6, &font).unwrap(),
+            [" 1.0", " 10.0", "100 "]
+        );
+    }
+
+    #[test]
+    fn measure_ranges() {
+        let mut font = make_font();
+        font.insert('W', 4);
+        font.insert('é', 2);
+
+        assert_eq!(measure_range("aWéa", 0..0, &font).unwrap(), 0);
+        assert_eq!(measure_range("aWéa", 1..4, &font).unwrap(), 6);
+        assert_eq!(measure_range("aWéa", 0..5, &font).unwrap(), 8);
+        assert_eq!(measure_range("a\nW", 0..3, &font).unwrap(), 5);
+
+        // Ranges have to be on character boundaries, inside the text
+        for range in [1..3, 3..5, 2..6] {
+            assert_eq!(
+                measure_range("aWéa", range.clone(), &font).unwrap_err(),
+                LineBreakErr::InvalidRange {
+                    start: range.start,
+                    end: range.end
+                }
+            );
+        }
+        assert_eq!(measure_range("a≤", 0..1, &font).unwrap(), 1);
+        assert_eq!(
+            measure_range("a≤", 0..4, &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+    }
+
+    #[test]
+    fn analysis() {
+        use BreakOpportunity::{Allowed, Mandatory};
+
+        let mut font = make_font();
+        font.insert('W', 4);
+        let info = |offset, opportunity, class, run_width, line_width| BreakInfo {
+            offset,
+            opportunity,
+            class,
+            run_width,
+            line_width,
+        };
+
+        assert_eq!(analyze("", &font).unwrap(), []);
+        assert_eq!(
+            analyze("aW  b\nc", &font).unwrap(),
+            [
+                info(4, Allowed, BreakClass::Space, 7, 7),
+                info(6, Mandatory, BreakClass::LineFeed, 1, 8),
+                info(7, Mandatory, BreakClass::Alphabetic, 1, 1),
+            ]
+        );
+
+        // Missing widths are only found once the scan gets to them
+        let mut iter = analyze_iter("a b≤ c", &font);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            info(2, Allowed, BreakClass::Space, 2, 2)
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+        assert!(iter.next().is_none());
+        assert_eq!(
+            analyze("a b≤ c", &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+    }
+
+    #[test]
+    fn unbreakable_width() {
+        let mut font = make_font();
+        font.insert('W', 4);
+
+        assert_eq!(min_unbreakable_width("", &font).unwrap(), 0);
+        assert_eq!(min_unbreakable_width("aa bbb c", &font).unwrap(), 4);
+        assert_eq!(min_unbreakable_width("aaa   b", &font).unwrap(), 6);
+        assert_eq!(min_unbreakable_width("aW-b\nccc", &font).unwrap(), 6);
+        assert_eq!(
+            min_unbreakable_width("a≤", &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+    }
+
+    #[test]
+    fn offset_at_rounds_to_nearest() {
+        let mut font = make_font();
+        font.insert('W', 4);
+
+        assert_eq!(offset_at("", 3, &font).unwrap(), 0);
+        assert_eq!(offset_at("aWa", 0, &font).unwrap(), 0);
+        assert_eq!(offset_at("aWa", 1, &font).unwrap(), 1);
+        assert_eq!(offset_at("aWa", 2, &font).unwrap(), 1);
+        assert_eq!(offset_at("aWa", 3, &font).unwrap(), 2);
+        assert_eq!(offset_at("aWa", 5, &font).unwrap(), 2);
+        assert_eq!(offset_at("aWa", 6, &font).unwrap(), 3);
+        assert_eq!(offset_at("aWa", 100, &font).unwrap(), 3);
+        assert_eq!(offset_at("aWa", usize::MAX, &font).unwrap(), 3);
+
+        // Multi-byte characters report byte offsets
+        font.insert('é', 2);
+        assert_eq!(offset_at("éa", 1, &font).unwrap(), 2);
+        assert_eq!(offset_at("éa", 2, &font).unwrap(), 2);
+
+        // The caret never moves past a hard line break
+        assert_eq!(offset_at("ab\ncd", 10, &font).unwrap(), 2);
+
+        assert_eq!(
+            offset_at("a≤", 5, &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+    }
+
+    #[test]
+    fn prefix_sums() {
+        let mut font = make_font();
+        font.insert('W', 4);
+        font.insert('é', 2);
+
+        assert_eq!(width_prefix_sums("", &font).unwrap(), [0]);
+        assert_eq!(width_prefix_sums("aWé", &font).unwrap(), [0, 1, 5, 7]);
+        assert_eq!(width_prefix_sums("a\nb", &font).unwrap(), [0, 1, 1, 2]);
+        assert_eq!(
+            width_prefix_sums("a≤", &font).unwrap_err(),
+            LineBreakErr::MissingCharacterWidth('≤')
+        );
+    }
+}
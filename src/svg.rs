@@ -0,0 +1,108 @@
+//! Writing laid out text as SVG.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{align, layout_detailed, Align, Direction, LayoutOptions, LineBreakErr};
+
+/// Wraps `string` and returns each line as an SVG `<tspan>` element, to go inside a `<text>`.
+///
+/// Line `n` is at `y = n * line_height`, so the baseline of the first line is at `0`, and each
+/// line has an absolute `x` and `y` rather than a `dy`, which would be lost on an empty line. Put
+/// the elements in a group with a `transform` to move them into place.
+///
+/// Lines are positioned with the widths from the font, as [`align`](crate::align) works them out
+/// for left-to-right text across `opts.max_width`, and `x` is the point `text-anchor` holds in
+/// place: the start of the line for [`Align::Start`], its middle for [`Align::Center`] and its
+/// end for [`Align::End`]. So the lines still line up if the renderer's font is a little wider or
+/// narrower than `font`. The whitespace at the end of each line is left out, and the rest is kept
+/// with `xml:space="preserve"`, since the widths count every space.
+///
+/// `&`, `<`, `>`, `"` and `'` are escaped. Control characters other than tab can't be written in
+/// XML at all, so set [`LayoutOptions::reject_controls`] if the text could have any.
+pub fn to_svg_tspans(
+    string: &str,
+    opts: &LayoutOptions,
+    font: &HashMap<char, usize>,
+    line_height: usize,
+    align_lines: Align,
+) -> Result<String, LineBreakErr> {
+    let result = layout_detailed(string, opts, font)?;
+    let lefts = align(&result, opts.max_width, align_lines, Direction::LeftToRight);
+    let anchor = match align_lines {
+        Align::Start => "start",
+        Align::Center => "middle",
+        Align::End => "end",
+    };
+
+    let mut svg = String::new();
+    let lines = result.lines().zip(result.trimmed_line_widths()).zip(lefts);
+    for (idx, ((line, width), left)) in lines.enumerate() {
+        let x = match align_lines {
+            Align::Start => left,
+            Align::Center => left + width / 2,
+            Align::End => left + width,
+        };
+        let y = idx * line_height;
+        write!(
+            svg,
+            r#"<tspan x="{x}" y="{y}" text-anchor="{anchor}" xml:space="preserve">"#
+        )
+        .map_err(LineBreakErr::Write)?;
+        escape_into(&mut svg, line.trim_end());
+        svg.push_str("</tspan>");
+    }
+    Ok(svg)
+}
+
+/// Writes `text` to `out` with the characters that are special in XML escaped.
+fn escape_into(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::make_font;
+
+    #[test]
+    fn tspans() {
+        let font = make_font();
+        let opts = LayoutOptions::new(10);
+
+        assert_eq!(
+            to_svg_tspans("Tom & Jerry <3", &opts, &font, 12, Align::Start).unwrap(),
+            concat!(
+                r#"<tspan x="0" y="0" text-anchor="start" xml:space="preserve">Tom &amp;</tspan>"#,
+                r#"<tspan x="0" y="12" text-anchor="start" xml:space="preserve">Jerry &lt;3</tspan>"#,
+            )
+        );
+
+        // "Tom &" is 5 wide, and "Jerry <3" is 8, in a width of 10
+        let centered = to_svg_tspans("Tom & Jerry <3", &opts, &font, 12, Align::Center).unwrap();
+        assert!(centered.starts_with(r#"<tspan x="4" y="0" text-anchor="middle""#));
+        assert!(centered.contains(r#"<tspan x="5" y="12" text-anchor="middle""#));
+        let right = to_svg_tspans("Tom & Jerry <3", &opts, &font, 12, Align::End).unwrap();
+        assert!(right.starts_with(r#"<tspan x="10" y="0" text-anchor="end""#));
+        assert!(right.contains(r#"<tspan x="10" y="12" text-anchor="end""#));
+
+        // Empty lines still take up their line
+        assert_eq!(
+            to_svg_tspans("a\n\n\"b\"", &opts, &font, 10, Align::Start).unwrap(),
+            concat!(
+                r#"<tspan x="0" y="0" text-anchor="start" xml:space="preserve">a</tspan>"#,
+                r#"<tspan x="0" y="10" text-anchor="start" xml:space="preserve"></tspan>"#,
+                r#"<tspan x="0" y="20" text-anchor="start" xml:space="preserve">&quot;b&quot;</tspan>"#,
+            )
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Wrapping text as it is written.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{layout, layout_detailed, LayoutOptions, LineBreakErr};
+
+/// A [`fmt::Write`] adapter that wraps the text written through it, like
+/// [`apply_newlines`](crate::apply_newlines), before passing it on to `inner`.
+///
+/// Text is held back until the lines it belongs to are complete, since the next write could
+/// still change where they break, so `write!` can be called with any pieces of the text, split
+/// anywhere. Call [`WrappingWriter::finish`] at the end to write the last line.
+///
+/// If wrapping fails, for example on a character missing from the font, or `inner` fails, the
+/// write returns [`fmt::Error`], as does every write after it, and [`WrappingWriter::finish`]
+/// returns what went wrong.
+#[derive(Debug)]
+pub struct WrappingWriter<'a, W> {
+    inner: W,
+    max_width: usize,
+    font: &'a HashMap<char, usize>,
+    /// The text written since the last complete line.
+    pending: String,
+    error: Option<LineBreakErr>,
+}
+
+impl<'a, W: fmt::Write> WrappingWriter<'a, W> {
+    /// Creates a writer that wraps text to `max_width` before writing it to `inner`.
+    pub fn new(inner: W, max_width: usize, font: &'a HashMap<char, usize>) -> Self {
+        WrappingWriter {
+            inner,
+            max_width,
+            font,
+            pending: String::new(),
+            error: None,
+        }
+    }
+
+    /// Writes the rest of the text, and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, LineBreakErr> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        let text = layout(
+            &self.pending,
+            &LayoutOptions::new(self.max_width),
+            self.font,
+        )?;
+        self.inner.write_str(&text).map_err(LineBreakErr::Write)?;
+        Ok(self.inner)
+    }
+
+    /// Writes out every line of the pending text that is known to be complete.
+    fn write_complete_lines(&mut self) -> Result<(), LineBreakErr> {
+        let result = layout_detailed(
+            &self.pending,
+            &LayoutOptions::new(self.max_width),
+            self.font,
+        )?;
+
+        // A line is complete once the next one has started: anything written later can only
+        // change the last line, or join a `\r` at the end to a `\n`
+        let complete = result
+            .line_starts
+            .iter()
+            .rposition(|&start| start < self.pending.len())
+            .unwrap_or(0);
+        if complete == 0 {
+            return Ok(());
+        }
+
+        let written = result.line_ranges()[complete].start;
+        self.inner
+            .write_str(&result.text[..written])
+            .map_err(LineBreakErr::Write)?;
+        self.pending.drain(..result.line_starts[complete]);
+        Ok(())
+    }
+}
+
+impl<W: fmt::Write> fmt::Write for WrappingWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.error.is_some() {
+            return Err(fmt::Error);
+        }
+        self.pending.push_str(s);
+        self.write_complete_lines().map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply_newlines;
+    use crate::tests::{make_font, Rng};
+    use std::fmt::Write;
+
+    #[test]
+    fn wraps_across_writes() {
+        let font = make_font();
+        let mut writer = WrappingWriter::new(String::new(), 8, &font);
+        let (first, rest) = ("hello", "world this is");
+        write!(writer, "{} {}", first, rest).unwrap();
+        assert_eq!(writer.inner, "hello \nworld \n");
+        write!(writer, " long\r").unwrap();
+        writer.write_str("\nenough").unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            "hello \nworld \nthis is \nlong\r\nenough"
+        );
+
+        let mut writer = WrappingWriter::new(String::new(), 3, &font);
+        assert!(write!(writer, "ab abcd ef").is_err());
+        assert!(writer.write_str("a").is_err());
+        assert!(matches!(
+            writer.finish().unwrap_err(),
+            LineBreakErr::NoLegalLinebreakOpportunity { offset: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn writes_match_apply_newlines() {
+        for seed in 0..1000 {
+            let mut rng = Rng::new(seed);
+            let text = rng.text();
+            let font = rng.font();
+            let max_width = 1 + rng.below(20);
+            let Ok(expected) = apply_newlines(&text, max_width, &font) else {
+                continue;
+            };
+
+            let mut writer = WrappingWriter::new(String::new(), max_width, &font);
+            let mut rest = text.as_str();
+            while !rest.is_empty() {
+                let chars = 1 + rng.below(6);
+                let split = rest
+                    .char_indices()
+                    .nth(chars)
+                    .map_or(rest.len(), |(idx, _)| idx);
+                writer.write_str(&rest[..split]).unwrap();
+                rest = &rest[split..];
+            }
+            assert_eq!(
+                writer.finish().unwrap(),
+                expected,
+                "seed {}: {:?}",
+                seed,
+                text
+            );
+        }
+    }
+}